@@ -0,0 +1,139 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::config::Config;
+use crate::proxy::Proxy;
+
+/// 到候选 IP 的 TCP 连接
+pub struct Connection {
+    pub stream: TcpStream,
+    /// 连接耗时, 经代理时为扣除本机到代理往返后的估算值
+    pub connect: Duration,
+    /// 经代理时本机到代理的往返耗时, 后续阶段的计时可据此扣除代理这一段; 直连时为 0
+    pub proxy_rtt: Duration,
+}
+
+/// 出站连接绑定的网卡 (`SO_BINDTODEVICE`)、源地址和上游代理
+///
+/// 延迟探测、trace 请求、ICMP 和速度测试都经过这里, 保证走同一条线路。
+#[derive(Debug, Clone, Default)]
+pub struct Binding {
+    pub interface: Option<String>,
+    pub address: Option<IpAddr>,
+    /// 设置后到候选 IP 的连接经代理隧道建立, 网卡和源地址作用于到代理的连接
+    pub via: Option<Proxy>,
+}
+
+impl Binding {
+    /// 多网卡时调用方为每个网卡克隆一份配置, 这里只取第一个
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            interface: config.interfaces.first().cloned(),
+            address: config.bind,
+            via: config.via.clone(),
+        }
+    }
+
+    /// 提前检查网卡和源地址是否可用, 避免所有探测都静默失败
+    pub fn check(&self) -> Result<()> {
+        let domain = match self.address {
+            Some(IpAddr::V6(_)) => Domain::IPV6,
+            _ => Domain::IPV4,
+        };
+        let socket = Socket::new(domain, Type::STREAM, None)?;
+        if let Some(name) = &self.interface {
+            bind_device(&socket, name).with_context(|| format!("无法绑定网卡 {}", name))?;
+        }
+        if let Some(ip) = self.address {
+            socket
+                .bind(&SocketAddr::new(ip, 0).into())
+                .with_context(|| format!("无法绑定源地址 {}", ip))?;
+        }
+        Ok(())
+    }
+
+    /// 建立到候选 IP 的 TCP 连接, 同时返回连接耗时
+    ///
+    /// 经代理时耗时为扣除本机到代理往返后的估算值。
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<Connection> {
+        match &self.via {
+            Some(proxy) => {
+                let tunnel = proxy.tunnel(self, addr).await?;
+                Ok(Connection {
+                    stream: tunnel.stream,
+                    connect: tunnel.connect,
+                    proxy_rtt: tunnel.proxy_rtt,
+                })
+            }
+            None => {
+                let start = Instant::now();
+                let stream = self.connect_direct(addr).await?;
+                Ok(Connection {
+                    stream,
+                    connect: start.elapsed(),
+                    proxy_rtt: Duration::ZERO,
+                })
+            }
+        }
+    }
+
+    /// 不经代理, 按网卡和源地址直接建立 TCP 连接
+    pub async fn connect_direct(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        self.apply(&socket, addr.ip())?;
+        socket.set_nonblocking(true)?;
+        let socket = TcpSocket::from_std_stream(socket.into());
+        socket.connect(addr).await
+    }
+
+    /// 对 ICMP 等自行创建的 socket 应用同样的绑定
+    pub fn apply(&self, socket: &Socket, target: IpAddr) -> io::Result<()> {
+        if let Some(name) = &self.interface {
+            bind_device(socket, name)?;
+        }
+        if let Some(ip) = self.source_for(target)? {
+            socket.bind(&SocketAddr::new(ip, 0).into())?;
+        }
+        Ok(())
+    }
+
+    /// 其他平台上 `check` 已拒绝 `--interface`, 这里只需设置源地址
+    pub fn apply_client(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let builder = match &self.interface {
+            Some(name) => builder.interface(name),
+            None => builder,
+        };
+        builder.local_address(self.address)
+    }
+
+    /// 源地址与目标地址族不一致时无法连接, 直接报错计为失败
+    fn source_for(&self, target: IpAddr) -> io::Result<Option<IpAddr>> {
+        match self.address {
+            Some(ip) if ip.is_ipv4() != target.is_ipv4() => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "源地址与目标地址族不一致",
+            )),
+            address => Ok(address),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, name: &str) -> io::Result<()> {
+    socket.bind_device(Some(name.as_bytes()))
+}
+
+/// `SO_BINDTODEVICE` 仅 Linux 和 Android 提供
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--interface 仅支持 Linux 和 Android",
+    ))
+}
@@ -0,0 +1,38 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// 带 HTTP 校验信息的缓存内容
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CachedBody {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub body: String,
+}
+
+/// 缓存目录: `$XDG_CACHE_HOME/cfip`, 未设置时为 `~/.cache/cfip`
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(base.join("cfip"))
+}
+
+pub fn load(name: &str) -> Option<CachedBody> {
+    let path = cache_dir()?.join(format!("{}.json", name));
+    let data = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&data).ok()
+}
+
+pub fn save(name: &str, entry: &CachedBody) -> Result<()> {
+    let dir = cache_dir().context("无法确定缓存目录")?;
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("创建缓存目录 '{}' 失败", dir.display()))?;
+
+    let path = dir.join(format!("{}.json", name));
+    std::fs::write(&path, serde_json::to_string(entry)?)
+        .with_context(|| format!("写入缓存 '{}' 失败", path.display()))?;
+    Ok(())
+}
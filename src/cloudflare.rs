@@ -76,16 +76,16 @@ pub async fn get_zone_id_by_name(
         ));
     }
 
-    if let Some(zones) = response.result {
-        if let Some(zone) = zones.into_iter().next() {
-            println!(
-                "Name='{}', Status='{}', ID='{}'\n",
-                zone.name.green(),
-                zone.status.green(),
-                zone.id.green()
-            );
-            return Ok(Some(zone.id));
-        }
+    if let Some(zones) = response.result
+        && let Some(zone) = zones.into_iter().next()
+    {
+        println!(
+            "Name='{}', Status='{}', ID='{}'\n",
+            zone.name.green(),
+            zone.status.green(),
+            zone.id.green()
+        );
+        return Ok(Some(zone.id));
     }
 
     Ok(None)
@@ -119,15 +119,16 @@ pub async fn get_dns_record_id(
         ));
     }
 
-    if let Some(records) = response.result {
-        if let Some(record) = records.into_iter().next() {
-            return Ok(Some(record.id));
-        }
+    if let Some(records) = response.result
+        && let Some(record) = records.into_iter().next()
+    {
+        return Ok(Some(record.id));
     }
 
     Ok(None)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn update_dns_record(
     client: &Client,
     api_token: &str,
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+/// 内置的 IATA 代码 -> 城市/国家对照表
+const COLO_TABLE: &str = include_str!("colos.csv");
+
+static COLOS: LazyLock<HashMap<&'static str, ColoInfo>> = LazyLock::new(|| {
+    COLO_TABLE
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(',');
+            let code = fields.next()?.trim();
+            let city = fields.next()?.trim();
+            let country = fields.next()?.trim();
+            Some((code, ColoInfo { city, country }))
+        })
+        .collect()
+});
+
+#[derive(Debug, Clone, Copy)]
+pub struct ColoInfo {
+    pub city: &'static str,
+    pub country: &'static str,
+}
+
+pub fn lookup(code: &str) -> Option<ColoInfo> {
+    COLOS.get(code.to_ascii_uppercase().as_str()).copied()
+}
+
+/// 从 `CF-RAY` 响应头 (形如 `8a1b2c3d4e5f6789-HKG`) 中提取节点代码
+pub fn from_cf_ray(ray: &str) -> Option<String> {
+    let (_, code) = ray.trim().rsplit_once('-')?;
+    (!code.is_empty()).then(|| code.to_ascii_uppercase())
+}
+
+/// `--colo` 和 `--country` 过滤条件, 同时指定时需同时满足
+#[derive(Debug, Clone, Default)]
+pub struct ColoFilter {
+    pub colos: Vec<String>,
+    pub countries: Vec<String>,
+}
+
+impl ColoFilter {
+    pub fn is_empty(&self) -> bool {
+        self.colos.is_empty() && self.countries.is_empty()
+    }
+
+    /// 未指定过滤条件时全部放行; 否则节点未知的 IP 一律拒绝
+    pub fn allows(&self, colo: Option<&str>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(colo) = colo else {
+            return false;
+        };
+
+        let colo_ok =
+            self.colos.is_empty() || self.colos.iter().any(|c| c.eq_ignore_ascii_case(colo));
+        let country_ok = self.countries.is_empty()
+            || lookup(colo).is_some_and(|info| {
+                self.countries
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(info.country))
+            });
+        colo_ok && country_ok
+    }
+}
@@ -0,0 +1,247 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// 为其他程序和本进程自身保留的文件描述符
+const FD_RESERVE: u64 = 128;
+
+/// 自动并发的起始值和上限
+const AUTO_START: usize = 64;
+const AUTO_MAX: usize = 4096;
+
+/// 窗口内超时比例高出历史最低值这么多, 视为并发过高引起的失败激增
+const SPIKE_MARGIN: f64 = 0.2;
+
+/// `--threads` 的取值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Threads {
+    Auto,
+    Fixed(usize),
+}
+
+pub fn parse_threads(spec: &str) -> Result<Threads, String> {
+    match spec {
+        "auto" => Ok(Threads::Auto),
+        _ => match spec.parse() {
+            Ok(0) | Err(_) => Err(format!("无效的并发数: {} (正整数或 auto)", spec)),
+            Ok(n) => Ok(Threads::Fixed(n)),
+        },
+    }
+}
+
+/// 本机资源不足导致的错误, 与目标无关, 不应计入丢包
+#[cfg(unix)]
+pub fn is_local_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EADDRNOTAVAIL)
+    )
+}
+
+/// Windows 上对应 WSAEMFILE、WSAEADDRNOTAVAIL 和 WSAENOBUFS
+#[cfg(not(unix))]
+pub fn is_local_error(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(10024 | 10049 | 10055))
+        || err.kind() == io::ErrorKind::OutOfMemory
+}
+
+/// 自动并发的起始值、上限和文件描述符软限制 (没有该限制的平台为 `None`)
+pub fn auto_limits() -> (usize, usize, Option<u64>) {
+    let soft = nofile_limit();
+    let max = match soft {
+        Some(soft) => (soft.saturating_sub(FD_RESERVE) as usize).clamp(1, AUTO_MAX),
+        None => AUTO_MAX,
+    };
+    (AUTO_START.min(max), max, soft)
+}
+
+/// 首次调用时把 RLIMIT_NOFILE 软限制提升到硬限制, 之后直接返回结果
+#[cfg(unix)]
+fn nofile_limit() -> Option<u64> {
+    static SOFT: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+    let soft = *SOFT.get_or_init(|| {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit/setrlimit 只读写传入的结构体
+        unsafe {
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0 {
+                return 1024;
+            }
+            if limit.rlim_cur < limit.rlim_max {
+                let raised = libc::rlimit {
+                    rlim_cur: limit.rlim_max,
+                    rlim_max: limit.rlim_max,
+                };
+                if libc::setrlimit(libc::RLIMIT_NOFILE, &raised) == 0 {
+                    limit.rlim_cur = limit.rlim_max;
+                }
+            }
+        }
+        limit.rlim_cur
+    });
+    Some(soft)
+}
+
+#[cfg(not(unix))]
+fn nofile_limit() -> Option<u64> {
+    None
+}
+
+/// 单次探测尝试的结果, 用于调整并发
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Reply,
+    /// 超时或被拒绝
+    Lost,
+    /// 本机资源错误 (EMFILE、ENOBUFS、EADDRNOTAVAIL 等)
+    Local,
+}
+
+/// 延迟测试的并发控制
+///
+/// 固定模式下就是一个信号量; 自动模式按 AIMD 调整许可数:
+/// 每完成一个窗口 (与当前并发数相同的尝试次数) 且没有异常时加一,
+/// 出现本机资源错误或超时比例激增时减半。
+pub struct Concurrency {
+    semaphore: Arc<Semaphore>,
+    aimd: Option<Mutex<Aimd>>,
+}
+
+struct Aimd {
+    limit: usize,
+    max: usize,
+    /// 减小并发时未能立即收回的许可, 在归还时扣除
+    debt: usize,
+    window_attempts: usize,
+    window_lost: usize,
+    decreased_in_window: bool,
+    best_lost_rate: f64,
+}
+
+impl Aimd {
+    fn decrease(&mut self, semaphore: &Semaphore) {
+        let target = (self.limit / 2).max(1);
+        let mut reduce = self.limit - target;
+        self.limit = target;
+        while reduce > 0
+            && let Ok(permit) = semaphore.try_acquire()
+        {
+            permit.forget();
+            reduce -= 1;
+        }
+        self.debt += reduce;
+        self.decreased_in_window = true;
+    }
+
+    fn increase(&mut self, semaphore: &Semaphore) {
+        if self.limit >= self.max {
+            return;
+        }
+        self.limit += 1;
+        if self.debt > 0 {
+            self.debt -= 1;
+        } else {
+            semaphore.add_permits(1);
+        }
+    }
+}
+
+/// 归还时若有未收回的许可则直接作废
+pub struct Permit {
+    permit: Option<OwnedSemaphorePermit>,
+    concurrency: Arc<Concurrency>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let Some(permit) = self.permit.take() else {
+            return;
+        };
+        if let Some(aimd) = &self.concurrency.aimd {
+            let mut aimd = aimd.lock().unwrap();
+            if aimd.debt > 0 {
+                aimd.debt -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl Concurrency {
+    pub fn new(threads: Threads) -> Arc<Self> {
+        match threads {
+            Threads::Fixed(n) => Arc::new(Self {
+                semaphore: Arc::new(Semaphore::new(n)),
+                aimd: None,
+            }),
+            Threads::Auto => {
+                let (start, max, _) = auto_limits();
+                Arc::new(Self {
+                    semaphore: Arc::new(Semaphore::new(start)),
+                    aimd: Some(Mutex::new(Aimd {
+                        limit: start,
+                        max,
+                        debt: 0,
+                        window_attempts: 0,
+                        window_lost: 0,
+                        decreased_in_window: false,
+                        best_lost_rate: 1.0,
+                    })),
+                })
+            }
+        }
+    }
+
+    pub async fn acquire(self: &Arc<Self>) -> Permit {
+        let permit = self.semaphore.clone().acquire_owned().await.unwrap();
+        Permit {
+            permit: Some(permit),
+            concurrency: self.clone(),
+        }
+    }
+
+    /// 当前并发上限, 固定模式返回 None
+    pub fn limit(&self) -> Option<usize> {
+        self.aimd.as_ref().map(|aimd| aimd.lock().unwrap().limit)
+    }
+
+    pub fn record(&self, outcome: Outcome) {
+        let Some(aimd) = &self.aimd else {
+            return;
+        };
+        let mut aimd = aimd.lock().unwrap();
+
+        match outcome {
+            // 同一窗口内的多次资源错误多半由同一批连接引起, 只减一次
+            Outcome::Local => {
+                if !aimd.decreased_in_window {
+                    aimd.decrease(&self.semaphore);
+                }
+                return;
+            }
+            Outcome::Lost => aimd.window_lost += 1,
+            Outcome::Reply => {}
+        }
+
+        aimd.window_attempts += 1;
+        if aimd.window_attempts < aimd.limit {
+            return;
+        }
+
+        let lost_rate = aimd.window_lost as f64 / aimd.window_attempts as f64;
+        if !aimd.decreased_in_window {
+            if lost_rate > aimd.best_lost_rate + SPIKE_MARGIN {
+                aimd.decrease(&self.semaphore);
+            } else {
+                aimd.increase(&self.semaphore);
+            }
+        }
+        aimd.best_lost_rate = aimd.best_lost_rate.min(lost_rate);
+        aimd.window_attempts = 0;
+        aimd.window_lost = 0;
+        aimd.decreased_in_window = false;
+    }
+}
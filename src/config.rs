@@ -1,24 +1,76 @@
-use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
-#[derive(Parser, Debug)]
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use ipnetwork::IpNetwork;
+
+use crate::colo::ColoFilter;
+use crate::concurrency::Threads;
+use crate::proxy::Proxy;
+
+/// Cloudflare 代理的 HTTPS 端口
+pub const HTTPS_PORTS: [u16; 6] = [443, 2053, 2083, 2087, 2096, 8443];
+/// Cloudflare 代理的 HTTP 端口
+pub const HTTP_PORTS: [u16; 7] = [80, 8080, 8880, 2052, 2082, 2086, 2095];
+
+/// Cloudflare 在这些端口上提供明文 HTTP, 其余端口按 HTTPS 处理
+pub fn is_http_port(port: u16) -> bool {
+    HTTP_PORTS.contains(&port)
+}
+
+fn parse_percent(spec: &str) -> Result<f64, String> {
+    match spec.parse::<f64>() {
+        Ok(value) if (0.0..=100.0).contains(&value) => Ok(value),
+        _ => Err(format!("无效的百分比: {} (0-100)", spec)),
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(name = "cfip", about = "Cloudflare 优选 IP 工具", version)]
 pub struct Config {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// 显示结果数量
     #[arg(short = 'n', long = "count", default_value_t = 10)]
     pub count: usize,
 
-    /// 延迟测试并发数
-    #[arg(short = 't', long = "threads", default_value_t = 200)]
-    pub threads: usize,
+    /// 延迟测试并发数, auto 根据文件描述符上限和失败情况自动调整
+    #[arg(short = 't', long = "threads", default_value = "200", value_parser = crate::concurrency::parse_threads)]
+    pub threads: Threads,
 
     /// 速度测试 IP 数量
     #[arg(short = 's', long = "speed-count", default_value_t = 10)]
     pub speed_count: usize,
 
+    /// 同时进行速度测试的 IP 数量 (并发测试共享本地带宽)
+    #[arg(long = "speed-threads", value_name = "N", default_value_t = 1)]
+    pub speed_threads: usize,
+
+    /// 并发速度测试时相邻两个测试开始的间隔 (毫秒)
+    #[arg(long = "speed-stagger", value_name = "MS", default_value_t = 0)]
+    pub speed_stagger_ms: u64,
+
     /// 测试端口
     #[arg(short = 'p', long = "port", default_value_t = 443)]
     pub port: u16,
 
+    /// 多端口探测 (逗号分隔), 每个 IP 在各端口上分别测试并取最优端口;
+    /// 支持预设 https-all (443,2053,2083,2087,2096,8443) 和 http-all (80,8080,8880,2052,2082,2086,2095)
+    #[arg(
+        long = "ports",
+        value_delimiter = ',',
+        value_name = "PORTS",
+        conflicts_with = "port"
+    )]
+    pub ports: Vec<String>,
+
+    /// 延迟探测方式: tcp 仅测 TCP 连接, http 完成 TLS 握手并请求 /cdn-cgi/trace (以完整请求耗时为延迟),
+    /// icmp 发送 ICMP echo, quic 完成 QUIC 握手 (UDP)
+    #[arg(long = "probe", value_enum, default_value_t = Probe::Tcp)]
+    pub probe: Probe,
+
     /// TCP 超时 (毫秒)
     #[arg(long = "timeout", default_value_t = 1000)]
     pub timeout_ms: u64,
@@ -27,6 +79,34 @@ pub struct Config {
     #[arg(long = "latency-limit", default_value_t = 300)]
     pub latency_limit_ms: u64,
 
+    /// 全局探测速率上限 (次/秒), 默认不限制
+    #[arg(long = "rate", value_name = "PROBES_PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate: Option<u32>,
+
+    /// 同一 IP 相邻两次探测的最小间隔 (毫秒), 等待期间不占用并发槽, 各 IP 的探测交错进行
+    #[arg(long = "probe-gap", value_name = "MS", default_value_t = 0)]
+    pub probe_gap_ms: u64,
+
+    /// 丢包率上限 (百分比), 超过视为不可用
+    #[arg(long = "max-loss", default_value_t = 50.0, value_parser = parse_percent)]
+    pub max_loss: f64,
+
+    /// 连续失败达到该次数后放弃该 IP, 剩余尝试计为丢包 (默认不启用)
+    #[arg(long = "abort-after", value_name = "N")]
+    pub abort_after: Option<usize>,
+
+    /// 找到这么多个满足条件的 IP 后提前结束延迟测试 (默认测完全部候选)
+    #[arg(long = "enough", value_name = "N")]
+    pub enough: Option<usize>,
+
+    /// 与 --latency-limit 比较及排序使用的延迟指标
+    #[arg(long = "latency-metric", value_enum, default_value_t = LatencyMetric::Avg)]
+    pub latency_metric: LatencyMetric,
+
+    /// 计算延迟统计前剔除偏高的离群样本 (Q3 + 1.5 IQR)
+    #[arg(long = "trim-outliers", default_value_t = false)]
+    pub trim_outliers: bool,
+
     /// 每个 IP 测试次数
     #[arg(long = "ping-times", default_value_t = 10)]
     pub ping_times: usize,
@@ -35,6 +115,19 @@ pub struct Config {
     #[arg(long = "download-size", default_value_t = 10_485_760)]
     pub download_size: usize,
 
+    /// 每个 IP 同时打开的下载连接数, 大于 1 时先测单连接再测多连接合计速度
+    #[arg(long = "connections", value_name = "N", default_value_t = 1)]
+    pub connections: usize,
+
+    /// 参与评分的速度指标: avg 总数据量除以总耗时, steady 预热后按 250 ms 采样的中位数, peak 采样峰值;
+    /// 单连接和上传速度也按该指标计算
+    #[arg(long = "speed-metric", value_enum, default_value_t = SpeedMetric::Avg)]
+    pub speed_metric: SpeedMetric,
+
+    /// 速度测试使用 HTTP/3 (QUIC)
+    #[arg(long = "speed-h3", default_value_t = false)]
+    pub speed_h3: bool,
+
     /// 速度测试 URL
     #[arg(
         long = "test-url",
@@ -42,12 +135,113 @@ pub struct Config {
     )]
     pub test_url: String,
 
+    /// 速度测试后再测上传速度
+    #[arg(long = "upload", default_value_t = false)]
+    pub upload: bool,
+
+    /// 上传测试 URL
+    #[arg(
+        long = "upload-url",
+        default_value = "https://speed.cloudflare.com/__up"
+    )]
+    pub upload_url: String,
+
+    /// 上传测试大小 (字节)
+    #[arg(long = "upload-size", default_value_t = 5_242_880)]
+    pub upload_size: u64,
+
+    /// 上传速度在综合分中的权重, 从下载速度的权重中扣除
+    #[arg(long = "upload-weight", default_value_t = 0.2)]
+    pub upload_weight: f64,
+
+    /// 探测和速度测试使用的源地址
+    #[arg(long = "bind", value_name = "IP")]
+    pub bind: Option<IpAddr>,
+
+    /// 探测和速度测试绑定的网卡 (SO_BINDTODEVICE), 指定多个时依次在每个网卡上扫描并标注结果
+    #[arg(long = "interface", value_delimiter = ',', value_name = "NAME")]
+    pub interfaces: Vec<String>,
+
+    /// 经上游代理连接候选 IP: socks5://[用户:密码@]主机:端口 或 http://主机:端口 (CONNECT)
+    #[arg(long = "via", value_name = "URL", value_parser = crate::proxy::parse_proxy)]
+    pub via: Option<Proxy>,
+
+    /// HTTP 探测和速度测试 TLS 握手使用的 SNI, 证书按此名称校验 (默认同 --host)
+    #[arg(long = "sni", value_name = "NAME")]
+    pub sni: Option<String>,
+
+    /// HTTP 探测和速度测试请求的 Host 头 (默认取 --test-url 的域名)
+    #[arg(long = "host", value_name = "NAME")]
+    pub host: Option<String>,
+
     /// 输出 CSV 文件路径
     #[arg(short = 'o', long = "output")]
     pub output: Option<String>,
 
+    /// IP 段来源 (可重复): cloudflare, fastly, cloudfront, text:<URL>, json:<URL>, file:<PATH>
+    /// (未指定时读取环境变量 CFIP_SOURCE, 逗号分隔)
+    #[arg(long = "source", value_name = "SOURCE", global = true)]
+    pub sources: Vec<String>,
+
+    /// JSON 来源的字段筛选, 如 service=CLOUDFRONT,region=GLOBAL
+    #[arg(
+        long = "source-filter",
+        value_delimiter = ',',
+        value_name = "KEY=VALUE",
+        global = true
+    )]
+    pub source_filters: Vec<String>,
+
+    /// 从文件读取 IP 列表 (可重复, `-` 表示标准输入), 等同于 --source file:<PATH>
+    #[arg(long = "ip-file", value_name = "PATH", global = true)]
+    pub ip_files: Vec<String>,
+
+    /// 解析域名列表文件中的域名 (A/AAAA), 将解析结果作为额外的候选 IP
+    #[arg(long = "from-domains", value_name = "PATH")]
+    pub from_domains: Option<String>,
+
+    /// 解析域名使用的 DNS 服务器 (IP 或 IP:端口), 默认使用系统解析器
+    #[arg(long = "dns-server", value_name = "ADDR", value_parser = crate::resolve::parse_dns_server)]
+    pub dns_server: Option<SocketAddr>,
+
+    /// 仅测试这些地址段内的 IP (逗号分隔的 CIDR)
+    #[arg(long = "include", value_delimiter = ',', value_name = "CIDR")]
+    pub include: Vec<IpNetwork>,
+
+    /// 排除这些地址段内的 IP (逗号分隔的 CIDR)
+    #[arg(long = "exclude", value_delimiter = ',', value_name = "CIDR")]
+    pub exclude: Vec<IpNetwork>,
+
+    /// IP 黑名单文件 (默认读取 ~/.config/cfip/blocklist.txt, 格式同 --ip-file)
+    #[arg(long = "blocklist", value_name = "PATH")]
+    pub blocklist: Option<PathBuf>,
+
+    /// 采样策略: fixed 按网段大小固定采样, adaptive 先每个 /24 探测一个 IP 再细扫最优子网
+    #[arg(long = "strategy", value_enum, default_value_t = Strategy::Fixed)]
+    pub strategy: Strategy,
+
+    /// 自适应策略第二阶段细扫的子网数量
+    #[arg(long = "top-subnets", default_value_t = 10)]
+    pub top_subnets: usize,
+
+    /// 自适应策略第二阶段每个子网的采样数量 (0 表示穷举)
+    #[arg(long = "subnet-samples", default_value_t = 16)]
+    pub subnet_samples: usize,
+
+    /// 仅保留落在这些 Cloudflare 节点的 IP (逗号分隔的 IATA 代码, 如 HKG,NRT,SJC)
+    #[arg(long = "colo", value_delimiter = ',', value_name = "IATA")]
+    pub colos: Vec<String>,
+
+    /// 仅保留落在这些国家/地区节点的 IP (逗号分隔的 ISO 代码, 如 HK,JP)
+    #[arg(long = "country", value_delimiter = ',', value_name = "CODE")]
+    pub countries: Vec<String>,
+
+    /// 随机种子, 指定后采样和测试顺序可复现 (未指定时随机生成并记录在输出中)
+    #[arg(long = "seed")]
+    pub seed: Option<u64>,
+
     /// 包含 IPv6
-    #[arg(short = '6', long = "ipv6")]
+    #[arg(short = '6', long = "ipv6", global = true)]
     pub ipv6: bool,
 
     /// Cloudflare API Token for DNS updates (read from CLOUDFLARE_API_TOKEN env var)
@@ -74,3 +268,87 @@ pub struct Config {
     #[arg(short = 'q', long = "quiet", default_value_t = false)]
     pub quiet: bool,
 }
+
+impl Config {
+    /// 展开 --ports 中的预设并去重, 未指定时为 --port
+    pub fn probe_ports(&self) -> Result<Vec<u16>> {
+        if self.ports.is_empty() {
+            return Ok(vec![self.port]);
+        }
+
+        let mut ports = Vec::new();
+        for spec in &self.ports {
+            let spec = spec.trim();
+            let expanded: Vec<u16> = match spec {
+                "https-all" => HTTPS_PORTS.to_vec(),
+                "http-all" => HTTP_PORTS.to_vec(),
+                _ => vec![
+                    spec.parse()
+                        .with_context(|| format!("无效的端口: {}", spec))?,
+                ],
+            };
+            for port in expanded {
+                if !ports.contains(&port) {
+                    ports.push(port);
+                }
+            }
+        }
+        Ok(ports)
+    }
+
+    /// 请求使用的 Host, 未指定 --host 时取 --test-url 的域名
+    pub fn request_host(&self) -> String {
+        self.host.clone().unwrap_or_else(|| {
+            reqwest::Url::parse(&self.test_url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_else(|| "speed.cloudflare.com".to_string())
+        })
+    }
+
+    /// TLS 握手使用的服务器名称, 未指定 --sni 时同 Host
+    pub fn tls_server_name(&self) -> String {
+        self.sni.clone().unwrap_or_else(|| self.request_host())
+    }
+
+    pub fn colo_filter(&self) -> ColoFilter {
+        ColoFilter {
+            colos: self.colos.clone(),
+            countries: self.countries.clone(),
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Tcp,
+    Http,
+    Icmp,
+    Quic,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyMetric {
+    Avg,
+    Median,
+    P95,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeedMetric {
+    Avg,
+    Steady,
+    Peak,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Fixed,
+    Adaptive,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// 打印当前使用的 IP 段及其来源 (在线、缓存、内置快照或本地文件)
+    Ranges,
+}
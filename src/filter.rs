@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use ipnetwork::IpNetwork;
+
+use crate::config::Config;
+use crate::ip;
+
+/// 地址段过滤的统计 (单位: 地址数)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RangeFilterStats {
+    pub include_removed: u128,
+    pub exclude_removed: u128,
+}
+
+/// 候选 IP 过滤的统计 (单位: 候选 IP 数, 每个 IP 只计一次)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CandidateFilterStats {
+    pub excluded: usize,
+    pub blocked: usize,
+}
+
+/// `--include`/`--exclude` 地址段过滤与 IP 黑名单
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub include: Vec<IpNetwork>,
+    pub exclude: Vec<IpNetwork>,
+    pub blocklist: Vec<IpNetwork>,
+    removed: Mutex<RemovedCandidates>,
+}
+
+/// 已剔除的候选 IP, 自适应扫描的两个阶段和多网卡会重复过滤同一 IP
+#[derive(Debug, Default)]
+struct RemovedCandidates {
+    excluded: HashSet<IpAddr>,
+    blocked: HashSet<IpAddr>,
+}
+
+/// 默认黑名单路径: `$XDG_CONFIG_HOME/cfip/blocklist.txt`, 未设置时为 `~/.config/cfip/blocklist.txt`
+pub fn default_blocklist_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("cfip").join("blocklist.txt"))
+}
+
+impl Filter {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        // 显式指定的黑名单必须存在, 默认路径不存在时忽略
+        let blocklist = match &config.blocklist {
+            Some(path) => load_blocklist(path)?,
+            None => match default_blocklist_path() {
+                Some(path) if path.exists() => load_blocklist(&path)?,
+                _ => Vec::new(),
+            },
+        };
+
+        Ok(Self {
+            include: config.include.clone(),
+            exclude: config.exclude.clone(),
+            blocklist,
+            removed: Mutex::default(),
+        })
+    }
+
+    /// 在采样前对地址段应用 `--include` 和 `--exclude`
+    pub fn filter_ranges(&self, ranges: &[IpNetwork]) -> (Vec<IpNetwork>, RangeFilterStats) {
+        let mut stats = RangeFilterStats::default();
+        let mut kept = Vec::new();
+
+        for &range in ranges {
+            let included = if self.include.is_empty() {
+                vec![range]
+            } else {
+                intersect(range, &self.include)
+            };
+            stats.include_removed = stats
+                .include_removed
+                .saturating_add(network_size(range).saturating_sub(total_size(&included)));
+
+            for net in included {
+                let remaining = subtract(net, &self.exclude);
+                stats.exclude_removed = stats
+                    .exclude_removed
+                    .saturating_add(network_size(net).saturating_sub(total_size(&remaining)));
+                kept.extend(remaining);
+            }
+        }
+
+        (kept, stats)
+    }
+
+    /// 在延迟测试前剔除黑名单及排除范围内的候选 IP
+    pub fn filter_candidates(&self, ips: Vec<IpAddr>) -> Vec<IpAddr> {
+        let mut removed = self.removed.lock().unwrap();
+        ips.into_iter()
+            .filter(|&ip| {
+                if self.exclude.iter().any(|net| net.contains(ip)) {
+                    removed.excluded.insert(ip);
+                    false
+                } else if self.blocklist.iter().any(|net| net.contains(ip)) {
+                    removed.blocked.insert(ip);
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect()
+    }
+
+    /// 已被 `--exclude` 和黑名单剔除的候选 IP 数量
+    pub fn candidate_stats(&self) -> CandidateFilterStats {
+        let removed = self.removed.lock().unwrap();
+        CandidateFilterStats {
+            excluded: removed.excluded.len(),
+            blocked: removed.blocked.len(),
+        }
+    }
+}
+
+fn load_blocklist(path: &std::path::Path) -> Result<Vec<IpNetwork>> {
+    let body = std::fs::read_to_string(path)
+        .with_context(|| format!("读取黑名单 '{}' 失败", path.display()))?;
+    let list = ip::parse_ip_list(&body)
+        .with_context(|| format!("解析黑名单 '{}' 失败", path.display()))?;
+    Ok(list.ranges)
+}
+
+fn host_bits(net: IpNetwork) -> u32 {
+    match net {
+        IpNetwork::V4(_) => 32 - net.prefix() as u32,
+        IpNetwork::V6(_) => 128 - net.prefix() as u32,
+    }
+}
+
+fn network_size(net: IpNetwork) -> u128 {
+    match host_bits(net) {
+        128 => u128::MAX,
+        bits => 1u128 << bits,
+    }
+}
+
+fn total_size(nets: &[IpNetwork]) -> u128 {
+    nets.iter()
+        .fold(0u128, |acc, &n| acc.saturating_add(network_size(n)))
+}
+
+fn intersect(range: IpNetwork, include: &[IpNetwork]) -> Vec<IpNetwork> {
+    if include.iter().any(|&inc| ip::covers(inc, range)) {
+        return vec![range];
+    }
+    // CIDR 之间要么嵌套要么不相交, 剩下的只可能是被 range 包含的 include 项;
+    // 按前缀从短到长处理, 跳过已被保留项覆盖的 include, 以免重叠部分重复计入
+    let mut nested: Vec<IpNetwork> = include
+        .iter()
+        .copied()
+        .filter(|&inc| ip::covers(range, inc))
+        .collect();
+    nested.sort_by_key(|net| net.prefix());
+    let mut kept: Vec<IpNetwork> = Vec::new();
+    for net in nested {
+        if !kept.iter().any(|&outer| ip::covers(outer, net)) {
+            kept.push(net);
+        }
+    }
+    kept
+}
+
+fn subtract(range: IpNetwork, exclude: &[IpNetwork]) -> Vec<IpNetwork> {
+    if exclude.iter().any(|&ex| ip::covers(ex, range)) {
+        return Vec::new();
+    }
+    if !exclude.iter().any(|&ex| ip::covers(range, ex)) {
+        return vec![range];
+    }
+
+    // 排除项位于 range 内部, 对半拆分后递归
+    let prefix = range.prefix() + 1;
+    let base = ip::ip_to_u128(range.network());
+    let upper = base | (1u128 << (host_bits(range) - 1));
+    let halves = [base, upper].map(|addr| {
+        IpNetwork::new(ip::u128_to_ip(addr, range.is_ipv4()), prefix)
+            .expect("子网前缀不超过地址长度")
+    });
+
+    halves
+        .into_iter()
+        .flat_map(|half| subtract(half, exclude))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(list: &[&str]) -> Vec<IpNetwork> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn strings(nets: &[IpNetwork]) -> Vec<String> {
+        nets.iter().map(|net| net.to_string()).collect()
+    }
+
+    #[test]
+    fn intersect_keeps_range_covered_by_include() {
+        let range = "10.0.1.0/24".parse().unwrap();
+        assert_eq!(
+            strings(&intersect(range, &nets(&["10.0.0.0/16"]))),
+            ["10.0.1.0/24"]
+        );
+    }
+
+    #[test]
+    fn intersect_narrows_to_nested_includes() {
+        let range = "10.0.0.0/16".parse().unwrap();
+        let include = nets(&["10.0.3.0/24", "10.0.9.128/25", "192.168.0.0/24"]);
+        assert_eq!(
+            strings(&intersect(range, &include)),
+            ["10.0.3.0/24", "10.0.9.128/25"]
+        );
+    }
+
+    #[test]
+    fn intersect_skips_overlapping_includes() {
+        let range = "10.0.0.0/16".parse().unwrap();
+        let include = nets(&["10.0.0.0/25", "10.0.0.0/24", "10.0.0.0/24", "10.0.5.0/24"]);
+        let included = intersect(range, &include);
+        assert_eq!(strings(&included), ["10.0.0.0/24", "10.0.5.0/24"]);
+        assert_eq!(total_size(&included), 512);
+    }
+
+    #[test]
+    fn intersect_disjoint_is_empty() {
+        let range = "10.0.0.0/24".parse().unwrap();
+        assert!(intersect(range, &nets(&["10.0.1.0/24", "2001:db8::/32"])).is_empty());
+    }
+
+    #[test]
+    fn subtract_without_overlap_keeps_range() {
+        let range = "10.0.0.0/24".parse().unwrap();
+        assert_eq!(
+            strings(&subtract(range, &nets(&["10.0.1.0/24"]))),
+            ["10.0.0.0/24"]
+        );
+    }
+
+    #[test]
+    fn subtract_covering_exclude_removes_range() {
+        let range = "10.0.0.0/24".parse().unwrap();
+        assert!(subtract(range, &nets(&["10.0.0.0/16"])).is_empty());
+    }
+
+    #[test]
+    fn subtract_splits_around_nested_exclude() {
+        let range = "10.0.0.0/24".parse().unwrap();
+        let remaining = subtract(range, &nets(&["10.0.0.64/26"]));
+        assert_eq!(strings(&remaining), ["10.0.0.0/26", "10.0.0.128/25"]);
+        assert_eq!(total_size(&remaining), 256 - 64);
+    }
+
+    #[test]
+    fn subtract_single_address() {
+        let range: IpNetwork = "2001:db8::/126".parse().unwrap();
+        let remaining = subtract(range, &nets(&["2001:db8::2/128"]));
+        assert_eq!(strings(&remaining), ["2001:db8::/127", "2001:db8::3/128"]);
+    }
+
+    #[test]
+    fn candidate_stats_count_each_ip_once() {
+        let filter = Filter {
+            exclude: nets(&["10.0.0.0/30"]),
+            blocklist: nets(&["10.0.0.0/29"]),
+            ..Filter::default()
+        };
+        let ips: Vec<IpAddr> = (1..=8).map(|i| IpAddr::from([10, 0, 0, i])).collect();
+
+        // 自适应扫描会对同一批 IP 再次过滤
+        let kept = filter.filter_candidates(ips.clone());
+        filter.filter_candidates(ips);
+
+        assert_eq!(kept, [IpAddr::from([10, 0, 0, 8])]);
+        let stats = filter.candidate_stats();
+        assert_eq!(stats.excluded, 3);
+        assert_eq!(stats.blocked, 4);
+    }
+}
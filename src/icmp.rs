@@ -0,0 +1,167 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::bind::Binding;
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+const PAYLOAD: &[u8] = b"cfip-icmp-probe!";
+
+/// ICMP socket 类型
+///
+/// 优先使用无需特权的 `SOCK_DGRAM` ping socket (受 `net.ipv4.ping_group_range` 控制),
+/// 不可用时退回需要 root 的原始 socket。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Dgram,
+    Raw,
+}
+
+/// 检测当前环境可用的 ICMP socket 类型
+pub fn detect_socket_kind() -> Result<SocketKind> {
+    if open_socket(Domain::IPV4, Type::DGRAM, Protocol::ICMPV4).is_ok() {
+        return Ok(SocketKind::Dgram);
+    }
+    if open_socket(Domain::IPV4, Type::RAW, Protocol::ICMPV4).is_ok() {
+        return Ok(SocketKind::Raw);
+    }
+    anyhow::bail!(
+        "无法创建 ICMP socket: 请调整 net.ipv4.ping_group_range 允许当前用户, 或以 root 运行"
+    )
+}
+
+fn open_socket(domain: Domain, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(domain, ty, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// 针对单个 IP 的 ICMP echo 探测, 自行维护标识符和序列号
+pub struct IcmpPinger {
+    socket: UdpSocket,
+    ip: IpAddr,
+    kind: SocketKind,
+    ident: u16,
+    seq: u16,
+}
+
+impl IcmpPinger {
+    pub fn new(ip: IpAddr, kind: SocketKind, binding: &Binding) -> io::Result<Self> {
+        let (domain, protocol) = match ip {
+            IpAddr::V4(_) => (Domain::IPV4, Protocol::ICMPV4),
+            IpAddr::V6(_) => (Domain::IPV6, Protocol::ICMPV6),
+        };
+        let ty = match kind {
+            SocketKind::Dgram => Type::DGRAM,
+            SocketKind::Raw => Type::RAW,
+        };
+
+        let socket = open_socket(domain, ty, protocol)?;
+        binding.apply(&socket, ip)?;
+        // connect 后内核只投递来自目标地址的报文
+        socket.connect(&SocketAddr::new(ip, 0).into())?;
+        let socket = UdpSocket::from_std(std::net::UdpSocket::from(socket))?;
+
+        Ok(Self {
+            socket,
+            ip,
+            kind,
+            ident: rand::random(),
+            seq: 0,
+        })
+    }
+
+    /// 发送一次 echo 请求并等待对应序列号的应答, 超时返回 `Ok(None)`, 发送失败返回错误
+    ///
+    /// 之前超时的请求迟到的应答序列号不匹配, 会被忽略。
+    pub async fn ping(&mut self, timeout: Duration) -> io::Result<Option<Duration>> {
+        self.seq = self.seq.wrapping_add(1);
+        let packet = self.echo_request();
+
+        let start = Instant::now();
+        let deadline = tokio::time::Instant::now() + timeout;
+        self.socket.send(&packet).await?;
+
+        let mut buf = [0u8; 1500];
+        loop {
+            let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Ok(Ok(len)) => len,
+                _ => return Ok(None),
+            };
+            if self.is_reply(&buf[..len]) {
+                return Ok(Some(start.elapsed()));
+            }
+        }
+    }
+
+    fn echo_request(&self) -> Vec<u8> {
+        let ty = match self.ip {
+            IpAddr::V4(_) => ECHO_REQUEST_V4,
+            IpAddr::V6(_) => ECHO_REQUEST_V6,
+        };
+
+        let mut packet = vec![ty, 0, 0, 0];
+        packet.extend_from_slice(&self.ident.to_be_bytes());
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(PAYLOAD);
+
+        // ICMPv6 和 ping socket 的校验和由内核填写
+        if self.ip.is_ipv4() && self.kind == SocketKind::Raw {
+            let sum = checksum(&packet);
+            packet[2..4].copy_from_slice(&sum.to_be_bytes());
+        }
+        packet
+    }
+
+    fn is_reply(&self, data: &[u8]) -> bool {
+        // IPv4 原始 socket 收到的报文带有 IP 头
+        let icmp = match (self.ip, self.kind) {
+            (IpAddr::V4(_), SocketKind::Raw) => {
+                let Some(&first) = data.first() else {
+                    return false;
+                };
+                let header_len = ((first & 0x0f) as usize) * 4;
+                data.get(header_len..).unwrap_or(&[])
+            }
+            _ => data,
+        };
+        if icmp.len() < 8 {
+            return false;
+        }
+
+        let reply_type = match self.ip {
+            IpAddr::V4(_) => ECHO_REPLY_V4,
+            IpAddr::V6(_) => ECHO_REPLY_V6,
+        };
+        let ident = u16::from_be_bytes([icmp[4], icmp[5]]);
+        let seq = u16::from_be_bytes([icmp[6], icmp[7]]);
+
+        // ping socket 的标识符由内核改写, 只能依靠序列号匹配
+        icmp[0] == reply_type
+            && seq == self.seq
+            && (self.kind == SocketKind::Dgram || ident == self.ident)
+    }
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => u16::from_be_bytes([*hi, *lo]) as u32,
+            [hi] => u16::from_be_bytes([*hi, 0]) as u32,
+            _ => 0,
+        })
+        .sum();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
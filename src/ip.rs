@@ -1,77 +1,121 @@
-use std::net::IpAddr;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use anyhow::{Context, Result};
 use ipnetwork::IpNetwork;
+use rand::Rng;
 use rand::seq::SliceRandom;
 
-const CF_IPV4_URL: &str = "https://www.cloudflare.com/ips-v4/";
-const CF_IPV6_URL: &str = "https://www.cloudflare.com/ips-v6/";
-
-pub async fn fetch_ip_ranges(include_ipv6: bool) -> Result<Vec<IpNetwork>> {
-    let mut ranges = Vec::new();
+/// 解析后的 IP 列表
+#[derive(Debug, Default)]
+pub struct IpList {
+    pub ranges: Vec<IpNetwork>,
+    /// `ranges` 中由 `a-b` 地址段拆分得到的地址块, 不是真实子网, 首尾地址同样参与采样
+    pub spans: Vec<IpNetwork>,
+}
 
-    let body = reqwest::get(CF_IPV4_URL)
-        .await
-        .context("获取 IPv4 地址段失败")?
-        .text()
-        .await?;
+/// 解析 IP 列表, 每行一个条目
+///
+/// 支持 CIDR、单个 IP 以及 `a.b.c.d-a.b.c.e` 形式的地址段, `#` 之后为注释。
+pub fn parse_ip_list(body: &str) -> Result<IpList> {
+    let mut list = IpList::default();
 
-    for line in body.lines() {
-        let line = line.trim();
-        if !line.is_empty() {
-            if let Ok(net) = line.parse::<IpNetwork>() {
-                ranges.push(net);
-            }
+    for (lineno, line) in body.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
         }
+
+        parse_ip_entry(line, &mut list)
+            .with_context(|| format!("第 {} 行无法解析: '{}'", lineno + 1, line))?;
     }
 
-    if include_ipv6 {
-        let body = reqwest::get(CF_IPV6_URL)
-            .await
-            .context("获取 IPv6 地址段失败")?
-            .text()
-            .await?;
+    Ok(list)
+}
 
-        for line in body.lines() {
-            let line = line.trim();
-            if !line.is_empty() {
-                if let Ok(net) = line.parse::<IpNetwork>() {
-                    ranges.push(net);
-                }
-            }
-        }
+fn parse_ip_entry(entry: &str, list: &mut IpList) -> Result<()> {
+    if let Some((start, end)) = entry.split_once('-') {
+        let start: IpAddr = start.trim().parse()?;
+        let end: IpAddr = end.trim().parse()?;
+        let blocks = range_to_networks(start, end)?;
+        list.ranges.extend(&blocks);
+        list.spans.extend(blocks);
+        return Ok(());
     }
 
-    if ranges.is_empty() {
-        anyhow::bail!("未获取到任何 Cloudflare IP 段");
+    if entry.contains('/') {
+        list.ranges.push(entry.parse::<IpNetwork>()?);
+        return Ok(());
     }
 
-    Ok(ranges)
+    let ip: IpAddr = entry.parse()?;
+    list.ranges.push(single_ip_network(ip));
+    Ok(())
 }
 
-pub fn sample_ips(ranges: &[IpNetwork]) -> Vec<IpAddr> {
-    let mut rng = rand::thread_rng();
-    let mut ips = Vec::new();
+fn single_ip_network(ip: IpAddr) -> IpNetwork {
+    let prefix = if ip.is_ipv4() { 32 } else { 128 };
+    IpNetwork::new(ip, prefix).expect("全长前缀总是合法的")
+}
 
-    for &network in ranges {
-        let prefix = network.prefix();
-        let hosts: Vec<IpAddr> = network.iter().collect();
+/// 将 `start-end` 地址段拆分为覆盖它的最少 CIDR 集合
+fn range_to_networks(start: IpAddr, end: IpAddr) -> Result<Vec<IpNetwork>> {
+    let bits = match (start, end) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => 32u32,
+        (IpAddr::V6(_), IpAddr::V6(_)) => 128u32,
+        _ => anyhow::bail!("地址段两端的地址族不一致"),
+    };
+    let (mut lo, hi) = (ip_to_u128(start), ip_to_u128(end));
 
-        // 排除网络地址和广播地址 (仅 IPv4 且子网 >= /31)
-        let usable: Vec<IpAddr> = if hosts.len() > 2 {
-            hosts[1..hosts.len() - 1].to_vec()
-        } else {
-            hosts
-        };
+    if lo > hi {
+        anyhow::bail!("地址段起始地址大于结束地址");
+    }
 
-        if usable.is_empty() {
-            continue;
+    let block_last = |lo: u128, size: u32| match size {
+        128 => u128::MAX,
+        _ => lo + ((1u128 << size) - 1),
+    };
+
+    let mut networks = Vec::new();
+    loop {
+        // 以 lo 为起点、对齐且不超过 hi 的最大块
+        let mut size = lo.trailing_zeros().min(bits);
+        while size > 0 && block_last(lo, size) > hi {
+            size -= 1;
+        }
+
+        networks.push(IpNetwork::new(
+            u128_to_ip(lo, bits == 32),
+            (bits - size) as u8,
+        )?);
+
+        let last = block_last(lo, size);
+        if last >= hi {
+            break;
         }
+        lo = last + 1;
+    }
+
+    Ok(networks)
+}
 
+/// `outer` 是否完整包含 `inner`
+pub fn covers(outer: IpNetwork, inner: IpNetwork) -> bool {
+    outer.prefix() <= inner.prefix() && outer.contains(inner.network())
+}
+
+/// 地址块是否来自 `a-b` 地址段 (包括过滤或分桶后得到的子块)
+pub fn in_spans(network: IpNetwork, spans: &[IpNetwork]) -> bool {
+    spans.iter().any(|&span| covers(span, network))
+}
+
+pub fn sample_ips<R: Rng>(ranges: &[IpNetwork], spans: &[IpNetwork], rng: &mut R) -> Vec<IpAddr> {
+    let mut ips = Vec::new();
+
+    for &network in ranges {
         let sample_count = match network {
-            IpNetwork::V4(_) => match prefix {
-                32 => 1,
-                31..=32 => usable.len(),
+            IpNetwork::V4(_) => match network.prefix() {
+                31..=32 => 2,
                 25..=30 => 2,
                 21..=24 => 5,
                 17..=20 => 10,
@@ -80,20 +124,360 @@ pub fn sample_ips(ranges: &[IpNetwork]) -> Vec<IpAddr> {
             IpNetwork::V6(_) => 5,
         };
 
-        let sample_count = sample_count.min(usable.len());
+        let whole = in_spans(network, spans);
+        ips.extend(sample_network(network, sample_count, whole, rng));
+    }
+
+    ips.shuffle(rng);
+    ips
+}
 
-        if sample_count >= usable.len() {
-            ips.extend(usable);
+/// 自适应扫描中 IPv4/IPv6 子网的前缀长度
+pub const BUCKET_PREFIX_V4: u8 = 24;
+pub const BUCKET_PREFIX_V6: u8 = 48;
+
+/// IPv6 网段的子网数量巨大, 每个网段最多随机选取这么多个 /48
+const MAX_V6_BUCKETS: usize = 256;
+
+/// 将地址段拆分为自适应扫描使用的子网 (IPv4 /24, IPv6 /48)
+pub fn subnet_buckets<R: Rng>(ranges: &[IpNetwork], rng: &mut R) -> Vec<IpNetwork> {
+    let mut buckets = Vec::new();
+
+    for &network in ranges {
+        let (bucket_prefix, max_buckets) = match network {
+            IpNetwork::V4(_) => (BUCKET_PREFIX_V4, usize::MAX),
+            IpNetwork::V6(_) => (BUCKET_PREFIX_V6, MAX_V6_BUCKETS),
+        };
+
+        if network.prefix() >= bucket_prefix {
+            buckets.push(network);
+            continue;
+        }
+
+        let base = ip_to_u128(network.network());
+        let shift = match network {
+            IpNetwork::V4(_) => 32 - bucket_prefix as u32,
+            IpNetwork::V6(_) => 128 - bucket_prefix as u32,
+        };
+        let total = 1u128 << (bucket_prefix - network.prefix());
+        let to_bucket = |index: u128| {
+            IpNetwork::new(
+                u128_to_ip(base | (index << shift), network.is_ipv4()),
+                bucket_prefix,
+            )
+            .expect("子网前缀不超过地址长度")
+        };
+
+        if total <= max_buckets as u128 {
+            buckets.extend((0..total).map(to_bucket));
         } else {
-            // 随机采样不重复的 IP
-            let mut indices: Vec<usize> = (0..usable.len()).collect();
-            indices.partial_shuffle(&mut rng, sample_count);
-            for &idx in &indices[..sample_count] {
-                ips.push(usable[idx]);
+            // 保持抽取顺序, 使相同种子得到相同结果
+            let mut seen = HashSet::with_capacity(max_buckets);
+            while seen.len() < max_buckets {
+                let index = rng.gen_range(0..total);
+                if seen.insert(index) {
+                    buckets.push(to_bucket(index));
+                }
             }
         }
     }
 
-    ips.shuffle(&mut rng);
-    ips
+    buckets
+}
+
+/// IPv6 接口标识的上限, 取较小的非零值
+const MAX_V6_IID: u128 = 0xffff;
+
+/// 在网段内随机抽取最多 `count` 个不重复地址, 不枚举整个网段
+///
+/// IPv4 排除网络地址和广播地址 (/31、/32 除外); IPv6 在 /64 以内随机选择子网,
+/// 任何前缀长度下接口标识都取较小的非零值 (不超过 0xffff), 避开子网路由器任播地址
+/// (/127、/128 除外)。
+/// `whole` 表示地址块来自 `a-b` 地址段而非真实子网, 此时块内所有地址都可能被抽取。
+pub fn sample_network<R: Rng>(
+    network: IpNetwork,
+    count: usize,
+    whole: bool,
+    rng: &mut R,
+) -> Vec<IpAddr> {
+    let base = ip_to_u128(network.network());
+    let host_bits = match network {
+        IpNetwork::V4(_) => 32 - network.prefix() as u32,
+        IpNetwork::V6(_) => 128 - network.prefix() as u32,
+    };
+
+    // 可用主机偏移量: 高位为 IPv6 的子网编号 (共 subnets 个 /64), 低位范围 [lo, hi]
+    let (subnets, lo, hi) = match (network, host_bits) {
+        (_, 0) => (1, 0, 0),
+        (_, 1) => (1, 0, 1),
+        (_, 128) if whole => (1, 0, u128::MAX),
+        (_, bits) if whole => (1, 0, (1u128 << bits) - 1),
+        (IpNetwork::V4(_), bits) => (1, 1, (1u128 << bits) - 2),
+        (IpNetwork::V6(_), bits) if bits > 64 => (1u128 << (bits - 64), 1, MAX_V6_IID),
+        (IpNetwork::V6(_), bits) => (1, 1, ((1u128 << bits) - 1).min(MAX_V6_IID)),
+    };
+
+    let to_addr = |offset: u128| u128_to_ip(base | offset, network.is_ipv4());
+
+    let total = (hi - lo)
+        .checked_add(1)
+        .and_then(|per_subnet| per_subnet.checked_mul(subnets));
+    if total.is_some_and(|total| total <= count as u128) {
+        return (0..subnets)
+            .flat_map(|subnet| (lo..=hi).map(move |offset| (subnet << 64) | offset))
+            .map(to_addr)
+            .collect();
+    }
+
+    // 保持抽取顺序, 使相同种子得到相同结果
+    let mut seen = HashSet::with_capacity(count);
+    let mut picked = Vec::with_capacity(count);
+    let mut attempts = 0;
+    while picked.len() < count && attempts < count.saturating_mul(20) {
+        attempts += 1;
+        let subnet = match subnets {
+            1 => 0,
+            n => rng.gen_range(0..n),
+        };
+        let offset = (subnet << 64) | rng.gen_range(lo..=hi);
+        if seen.insert(offset) {
+            picked.push(offset);
+        }
+    }
+
+    picked.into_iter().map(to_addr).collect()
+}
+
+pub fn ip_to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+pub fn u128_to_ip(value: u128, v4: bool) -> IpAddr {
+    if v4 {
+        IpAddr::V4(Ipv4Addr::from(value as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    fn networks(start: &str, end: &str) -> Vec<String> {
+        range_to_networks(start.parse().unwrap(), end.parse().unwrap())
+            .unwrap()
+            .iter()
+            .map(|net| net.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn range_single_address() {
+        assert_eq!(networks("10.0.0.5", "10.0.0.5"), ["10.0.0.5/32"]);
+        assert_eq!(networks("2001:db8::1", "2001:db8::1"), ["2001:db8::1/128"]);
+    }
+
+    #[test]
+    fn range_aligned_block() {
+        assert_eq!(networks("10.0.0.0", "10.0.0.255"), ["10.0.0.0/24"]);
+        assert_eq!(networks("2001:db8::", "2001:db8::ffff"), ["2001:db8::/112"]);
+    }
+
+    #[test]
+    fn range_unaligned_bounds() {
+        assert_eq!(
+            networks("10.0.0.1", "10.0.0.10"),
+            [
+                "10.0.0.1/32",
+                "10.0.0.2/31",
+                "10.0.0.4/30",
+                "10.0.0.8/31",
+                "10.0.0.10/32",
+            ]
+        );
+        assert_eq!(
+            networks("192.168.0.255", "192.168.2.0"),
+            ["192.168.0.255/32", "192.168.1.0/24", "192.168.2.0/32"]
+        );
+    }
+
+    #[test]
+    fn range_full_address_space() {
+        assert_eq!(networks("0.0.0.0", "255.255.255.255"), ["0.0.0.0/0"]);
+        assert_eq!(
+            networks("::", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"),
+            ["::/0"]
+        );
+    }
+
+    #[test]
+    fn range_ends_at_top_of_space() {
+        assert_eq!(
+            networks("255.255.255.254", "255.255.255.255"),
+            ["255.255.255.254/31"]
+        );
+    }
+
+    #[test]
+    fn range_rejects_invalid_bounds() {
+        let v4: IpAddr = "10.0.0.2".parse().unwrap();
+        let v4_lower: IpAddr = "10.0.0.1".parse().unwrap();
+        let v6: IpAddr = "::1".parse().unwrap();
+        assert!(range_to_networks(v4, v4_lower).is_err());
+        assert!(range_to_networks(v4, v6).is_err());
+    }
+
+    #[test]
+    fn range_blocks_keep_their_bounds() {
+        let list = parse_ip_list("10.0.0.4-10.0.0.7\n10.0.1.0/30\n").unwrap();
+        assert_eq!(list.ranges.len(), 2);
+        assert_eq!(list.spans, [list.ranges[0]]);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut range: Vec<IpAddr> = sample_network(
+            list.ranges[0],
+            10,
+            in_spans(list.ranges[0], &list.spans),
+            &mut rng,
+        );
+        range.sort();
+        let expected: Vec<IpAddr> = (4..=7).map(|i| IpAddr::from([10, 0, 0, i])).collect();
+        assert_eq!(range, expected);
+
+        // 真实子网仍然排除网络地址和广播地址
+        let mut subnet = sample_network(
+            list.ranges[1],
+            10,
+            in_spans(list.ranges[1], &list.spans),
+            &mut rng,
+        );
+        subnet.sort();
+        assert_eq!(
+            subnet,
+            [IpAddr::from([10, 0, 1, 1]), IpAddr::from([10, 0, 1, 2])]
+        );
+    }
+
+    #[test]
+    fn range_sampling_reaches_every_address() {
+        let list = parse_ip_list("10.0.0.4-10.0.0.7").unwrap();
+        let mut seen = HashSet::new();
+        for seed in 0..64 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            seen.extend(sample_ips(&list.ranges, &list.spans, &mut rng));
+        }
+        assert_eq!(seen.len(), 4);
+    }
+
+    #[test]
+    fn spans_cover_their_sub_blocks() {
+        let spans = [IpNetwork::new("10.0.0.0".parse().unwrap(), 16).unwrap()];
+        assert!(in_spans("10.0.3.0/24".parse().unwrap(), &spans));
+        assert!(!in_spans("10.1.0.0/24".parse().unwrap(), &spans));
+        assert!(!in_spans("10.0.0.0/8".parse().unwrap(), &spans));
+    }
+
+    fn sample(net: &str, count: usize, seed: u64) -> Vec<IpAddr> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut ips = sample_network(net.parse().unwrap(), count, false, &mut rng);
+        ips.sort();
+        ips
+    }
+
+    fn addrs(list: &[&str]) -> Vec<IpAddr> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    /// 地址的低 64 位, 即 IPv6 接口标识
+    fn iid(ip: IpAddr) -> u128 {
+        ip_to_u128(ip) & u64::MAX as u128
+    }
+
+    #[test]
+    fn sample_v4_host_routes() {
+        assert_eq!(sample("10.0.0.7/32", 5, 0), addrs(&["10.0.0.7"]));
+        assert_eq!(
+            sample("10.0.0.6/31", 5, 0),
+            addrs(&["10.0.0.6", "10.0.0.7"])
+        );
+    }
+
+    #[test]
+    fn sample_v4_excludes_network_and_broadcast() {
+        assert_eq!(
+            sample("10.0.0.4/30", 5, 0),
+            addrs(&["10.0.0.5", "10.0.0.6"])
+        );
+
+        let ips = sample("10.0.0.0/24", 300, 0);
+        assert_eq!(ips.len(), 254);
+        assert_eq!(ips[0], "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(ips[253], "10.0.0.254".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn sample_v4_whole_space() {
+        let ips = sample("0.0.0.0/0", 50, 3);
+        assert_eq!(ips.len(), 50);
+        assert_eq!(ips.iter().collect::<HashSet<_>>().len(), 50);
+        assert!(!ips.contains(&"0.0.0.0".parse().unwrap()));
+        assert!(!ips.contains(&"255.255.255.255".parse().unwrap()));
+    }
+
+    #[test]
+    fn sample_v6_host_routes() {
+        assert_eq!(sample("2001:db8::5/128", 5, 0), addrs(&["2001:db8::5"]));
+        assert_eq!(
+            sample("2001:db8::4/127", 5, 0),
+            addrs(&["2001:db8::4", "2001:db8::5"])
+        );
+    }
+
+    #[test]
+    fn sample_v6_small_nonzero_interface_ids() {
+        for net in [
+            "2001:db8::/32",
+            "2001:db8::/64",
+            "2001:db8::/96",
+            "2001:db8::/112",
+        ] {
+            let network: IpNetwork = net.parse().unwrap();
+            let ips = sample(net, 50, 7);
+            assert_eq!(ips.len(), 50, "{}", net);
+            for ip in ips {
+                assert!(network.contains(ip), "{} 不在 {} 内", ip, net);
+                assert!((1..=0xffff).contains(&iid(ip)), "{} 的接口标识过大", ip);
+            }
+        }
+    }
+
+    #[test]
+    fn sample_v6_spreads_over_subnets() {
+        let ips = sample("2001:db8::/32", 50, 7);
+        let subnets: HashSet<u128> = ips.iter().map(|&ip| ip_to_u128(ip) >> 64).collect();
+        assert!(subnets.len() > 1);
+    }
+
+    #[test]
+    fn sample_v6_short_host_part_is_enumerated() {
+        let ips = sample("2001:db8::/120", 500, 0);
+        assert_eq!(ips.len(), 255);
+        assert!(!ips.contains(&"2001:db8::".parse().unwrap()));
+    }
+
+    #[test]
+    fn sample_is_reproducible() {
+        assert_eq!(
+            sample("2001:db8::/32", 20, 42),
+            sample("2001:db8::/32", 20, 42)
+        );
+        assert_eq!(sample("10.0.0.0/8", 20, 42), sample("10.0.0.0/8", 20, 42));
+    }
 }
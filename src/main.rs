@@ -1,10 +1,26 @@
+mod bind;
+mod cache;
 mod cloudflare;
+mod colo;
+mod concurrency;
 mod config;
+mod filter;
+mod icmp;
 mod ip;
 mod output;
 mod ping;
+mod proxy;
+mod quic;
+mod rate;
+mod resolve;
+mod scan;
 mod score;
-mod speed; // Add cloudflare module
+mod source;
+mod speed;
+mod stats;
+mod status;
+mod throughput;
+mod trace; // Add cloudflare module
 
 use anyhow::Result;
 use clap::Parser;
@@ -14,7 +30,8 @@ use reqwest::Client;
 use std::env;
 use std::io::{self, Write}; // Import io and Write // Import reqwest::Client
 
-use config::Config;
+use config::{Command, Config, Probe};
+use filter::Filter;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -22,6 +39,51 @@ async fn main() -> Result<()> {
 
     let mut config = Config::parse();
 
+    if config.sources.is_empty()
+        && let Ok(spec) = env::var("CFIP_SOURCE")
+    {
+        config.sources = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+    let sources = source::from_config(&config)?;
+    let ports = config.probe_ports()?;
+    if config.via.is_some()
+        && (matches!(config.probe, Probe::Icmp | Probe::Quic) || config.speed_h3)
+    {
+        anyhow::bail!("--via 代理只能转发 TCP, 不支持 --probe icmp/quic 和 --speed-h3");
+    }
+    if !(0.0..=score::SPEED_WEIGHT).contains(&config.upload_weight) {
+        anyhow::bail!("--upload-weight 须在 0 到 {} 之间", score::SPEED_WEIGHT);
+    }
+
+    // 多个网卡时依次扫描, 每次只绑定其中一个
+    let runs: Vec<Config> = if config.interfaces.len() > 1 {
+        config
+            .interfaces
+            .iter()
+            .map(|name| {
+                let mut run = config.clone();
+                run.interfaces = vec![name.clone()];
+                run
+            })
+            .collect()
+    } else {
+        vec![config.clone()]
+    };
+    for run in &runs {
+        bind::Binding::from_config(run).check()?;
+    }
+
+    if let Some(Command::Ranges) = config.command {
+        let lists = source::fetch_all(&sources, config.ipv6).await?;
+        output::print_range_lists(&lists);
+        return Ok(());
+    }
+
     config.cloudflare_api_token = env::var("CLOUDFLARE_API_TOKEN").ok();
     config.cloudflare_zone_id = env::var("CLOUDFLARE_ZONE_ID").ok();
     config.cloudflare_record_name = env::var("CLOUDFLARE_RECORD_NAME").ok();
@@ -102,34 +164,94 @@ async fn main() -> Result<()> {
     }
     // --- Cloudflare DNS Update Pre-checks and Dynamic ZONE_ID Fetching (Optimization End) ---
 
-    // 1. 获取 Cloudflare IP 段
-    let ranges = ip::fetch_ip_ranges(config.ipv6).await?;
+    // 1. 获取 IP 段
+    let lists = source::fetch_all(&sources, config.ipv6).await?;
+    let spans: Vec<_> = lists.iter().flat_map(|list| list.spans.clone()).collect();
+    let ranges: Vec<_> = lists.into_iter().flat_map(|list| list.ranges).collect();
 
-    // 2. 随机采样 IP
-    let ips = ip::sample_ips(&ranges);
+    let filter = Filter::from_config(&config)?;
+    let (ranges, range_stats) = filter.filter_ranges(&ranges);
+    if ranges.is_empty() {
+        anyhow::bail!("应用 --include/--exclude 后没有剩余的 IP 段");
+    }
 
-    // 3. 延迟测试
+    let domain_ips = match &config.from_domains {
+        Some(path) => {
+            let ips = resolve::resolve_domain_file(path, config.dns_server, config.ipv6).await?;
+            println!("域名解析得到 {} 个候选 IP", ips.len());
+            ips
+        }
+        None => Vec::new(),
+    };
+
+    // 2. 采样 IP 并测试延迟
+    let seed = config.seed.unwrap_or_else(rand::random);
     println!("{}", "* 延迟测试".cyan().bold());
-    let ping_results = ping::test_latency(&ips, &config).await?;
-    println!(
-        "{}",
-        format!("延迟测试完成，{} 个 IP 通过筛选。\n", ping_results.len()).green()
-    );
+    println!("随机种子: {} (使用 --seed {} 可重放本次采样)", seed, seed);
+    if (config.sni.is_some() || config.host.is_some())
+        && matches!(config.probe, Probe::Tcp | Probe::Icmp)
+    {
+        println!(
+            "{}",
+            "提示: TCP/ICMP 探测不经过 TLS, --sni/--host 仅对节点识别和速度测试生效, 检测 SNI 过滤请使用 --probe http".yellow()
+        );
+    }
+    if config.threads == concurrency::Threads::Auto {
+        let (start, max, nofile) = concurrency::auto_limits();
+        match nofile {
+            Some(nofile) => println!(
+                "并发: 自动 (起始 {}, 上限 {}, 文件描述符上限 {})",
+                start, max, nofile
+            ),
+            None => println!("并发: 自动 (起始 {}, 上限 {})", start, max),
+        }
+    }
+    if ports.len() > 1 {
+        let list: Vec<String> = ports.iter().map(u16::to_string).collect();
+        println!("探测端口: {} (每个 IP 取最优端口)", list.join(", "));
+    }
+    let mut latency_runs = Vec::with_capacity(runs.len());
+    for run in &runs {
+        if runs.len() > 1 {
+            println!("{}", format!("网卡 {}:", run.interfaces[0]).cyan());
+        }
+        let ping_results =
+            scan::run_latency_stage(&ranges, &spans, &domain_ips, run, &filter, seed).await?;
+        println!(
+            "{}",
+            format!("延迟测试完成，{} 个 IP 通过筛选。\n", ping_results.len()).green()
+        );
+
+        if !domain_ips.is_empty() {
+            output::print_dns_baseline(&ping_results, &domain_ips, run.latency_metric);
+        }
+        latency_runs.push(ping_results);
+    }
+    if !filter.include.is_empty() || !filter.exclude.is_empty() || !filter.blocklist.is_empty() {
+        let candidate_stats = filter.candidate_stats();
+        println!(
+            "过滤统计: --include 移除 {} 个地址, --exclude 移除 {} 个地址和 {} 个候选 IP, 黑名单移除 {} 个候选 IP",
+            range_stats.include_removed,
+            range_stats.exclude_removed,
+            candidate_stats.excluded,
+            candidate_stats.blocked
+        );
+    }
 
     // Recommendation for proxy testing if latency is very low
-    if !ping_results.is_empty() {
-        let min_latency_ms = ping_results
-            .iter()
-            .map(|r| r.avg_latency.as_millis())
-            .min()
-            .unwrap_or(u128::MAX); // Get the minimum latency in milliseconds
+    let min_latency_ms = latency_runs
+        .iter()
+        .flatten()
+        .map(|r| r.avg_latency.as_millis())
+        .min()
+        .unwrap_or(u128::MAX); // Get the minimum latency in milliseconds
 
-        if min_latency_ms < 5 {
-            println!("{}", "提示: 检测到极低延迟 (小于 5ms)。\n如果您正在使用代理测试，建议关闭代理以获得更准确的 Cloudflare 优选 IP。\n".yellow());
-        }
+    // --via 时延迟已扣除到代理的往返, 不再提示
+    if min_latency_ms < 5 && config.via.is_none() {
+        println!("{}", "提示: 检测到极低延迟 (小于 5ms)。\n如果您正在使用代理测试，建议关闭代理以获得更准确的 Cloudflare 优选 IP。\n".yellow());
     }
 
-    if ping_results.is_empty() {
+    if latency_runs.iter().all(Vec::is_empty) {
         println!(
             "{}",
             "没有 IP 通过延迟筛选，请尝试增大 --latency-limit".red()
@@ -137,27 +259,41 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    // 4. 速度测试
+    // 3. 速度测试
     println!("{}", "* 速度测试".cyan().bold());
-    let speed_results = speed::test_speed(&ping_results, &config).await?;
+    if config.speed_threads > 1 {
+        println!(
+            "{}",
+            format!(
+                "提示: {} 路并发测速共享本地带宽, 单 IP 速度可能偏低; 总吞吐接近本地带宽时瓶颈在本地而非 IP。",
+                config.speed_threads
+            )
+            .yellow()
+        );
+    }
+    let mut speed_results = Vec::new();
+    for (run, ping_results) in runs.iter().zip(&latency_runs) {
+        speed_results.extend(speed::test_speed(ping_results, run).await?);
+    }
 
     if speed_results.is_empty() {
         println!("{}", "没有 IP 通过速度测试".red());
         return Ok(());
     }
 
-    // 5. 综合评分
-    let scored = score::calculate_scores(&speed_results);
+    // 4. 综合评分
+    let scored =
+        score::calculate_scores(&speed_results, config.latency_metric, config.upload_weight);
 
-    // 6. 输出结果
-    output::print_results(&scored, config.count);
+    // 5. 输出结果
+    output::print_results(&scored, config.count, config.latency_metric);
 
     if let Some(ref path) = config.output {
-        output::write_csv(&scored, path)?;
+        output::write_csv(&scored, path, seed, config.latency_metric)?;
         println!("{}", format!("结果已保存到 '{}'。", path.green()).green());
     }
 
-    // 7. Cloudflare DNS 更新
+    // 6. Cloudflare DNS 更新
     if needs_cloudflare_update {
         // Safe to unwrap here because needs_cloudflare_update is true and checks before would have caught None
         let api_token = config.cloudflare_api_token.as_ref().unwrap();
@@ -174,7 +310,7 @@ async fn main() -> Result<()> {
         let optimal_ip_str: String;
 
         if config.quiet {
-            if let Some(ip) = scored.first().map(|s| s.ip.to_string()) {
+            if let Some(ip) = scored.first().map(|s| s.ping.ip.to_string()) {
                 optimal_ip_str = ip;
                 println!(
                     "{}",
@@ -201,10 +337,10 @@ async fn main() -> Result<()> {
                 println!(
                     "{}. {} (延迟: {}ms, 速度: {:.2}MB/s, 丢包率: {}%)",
                     i + 1,
-                    entry.ip.to_string().green(),
-                    entry.latency.as_millis(), // Use .as_millis() for Duration
+                    entry.ping.ip.to_string().green(),
+                    entry.ping.stats.metric(config.latency_metric).as_millis(),
                     entry.speed_bps / 8_000_000.0, // Correctly convert bits per second to MB/s
-                    entry.loss_rate * 100.0
+                    entry.ping.loss_rate * 100.0
                 );
             }
             println!("{}. {}", "0".yellow(), "取消更新".yellow());
@@ -239,7 +375,7 @@ async fn main() -> Result<()> {
             }
 
             optimal_ip_str = if let Some(index) = selected_index {
-                scored[index].ip.to_string()
+                scored[index].ping.ip.to_string()
             } else {
                 eprintln!("{}", "没有有效选择，跳过 Cloudflare DNS 更新。".red());
                 return Ok(());
@@ -1,8 +1,15 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
 use anyhow::Result;
 use colored::Colorize;
 use comfy_table::{Cell, Color, Table, modifiers::UTF8_ROUND_CORNERS, presets::UTF8_FULL};
 
+use crate::colo;
+use crate::config::LatencyMetric;
+use crate::ping::PingResult;
 use crate::score::ScoredResult;
+use crate::source::RangeList;
 
 fn format_speed(bps: f64) -> String {
     let mbps = bps / 1_048_576.0;
@@ -14,6 +21,23 @@ fn format_speed(bps: f64) -> String {
     }
 }
 
+fn format_ms(value: Option<Duration>, missing: &str) -> String {
+    match value {
+        Some(d) => format!("{:.1}", d.as_secs_f64() * 1000.0),
+        None => missing.to_string(),
+    }
+}
+
+fn format_colo(code: Option<&str>) -> String {
+    match code {
+        Some(code) => match colo::lookup(code) {
+            Some(info) => format!("{} ({}, {})", code, info.city, info.country),
+            None => code.to_string(),
+        },
+        None => "-".to_string(),
+    }
+}
+
 fn latency_color(ms: f64) -> Color {
     if ms < 100.0 {
         Color::Green
@@ -35,7 +59,8 @@ fn speed_color(bps: f64) -> Color {
     }
 }
 
-pub fn print_results(results: &[ScoredResult], count: usize) {
+/// "延迟" 列显示 `--latency-metric` 选择的指标, 其余分布统计单独成列
+pub fn print_results(results: &[ScoredResult], count: usize, metric: LatencyMetric) {
     let display = &results[..count.min(results.len())];
 
     if display.is_empty() {
@@ -43,34 +68,142 @@ pub fn print_results(results: &[ScoredResult], count: usize) {
         return;
     }
 
+    // HTTP 探测时额外显示 TLS 握手和首字节耗时
+    let show_http = display.iter().any(|r| r.ping.tls_handshake.is_some());
+
+    let show_colo = display.iter().any(|r| r.ping.colo.is_some());
+
+    let show_quic = display.iter().any(|r| r.ping.quic_handshake.is_some());
+
+    // 多端口探测或非默认端口时显示各 IP 的最优端口
+    let show_port = display.iter().any(|r| r.ping.port != 443);
+
+    let show_interface = display.iter().any(|r| r.ping.interface.is_some());
+
+    let show_stream = display.iter().any(|r| r.stream_bps.is_some());
+
+    let show_upload = display.iter().any(|r| r.upload_bps.is_some());
+
+    let mut header = vec!["排名", "IP 地址"];
+    if show_interface {
+        header.push("网卡");
+    }
+    if show_port {
+        header.push("端口");
+    }
+    if show_colo {
+        header.push("节点");
+    }
+    let latency_header = match metric {
+        LatencyMetric::Avg => "延迟 (平均)",
+        LatencyMetric::Median => "延迟 (中位数)",
+        LatencyMetric::P95 => "延迟 (P95)",
+    };
+    header.extend([
+        latency_header,
+        "最小",
+        "最大",
+        "中位数",
+        "P95",
+        "标准差",
+        "抖动",
+    ]);
+    if show_http {
+        header.extend(["TLS 握手", "首字节"]);
+    }
+    if show_quic {
+        header.extend(["QUIC 握手", "QUIC 成功率"]);
+    }
+    header.extend(["丢包率", "速度", "稳定", "峰值"]);
+    if show_stream {
+        header.push("单连接");
+    }
+    if show_upload {
+        header.push("上传");
+    }
+    header.push("综合分");
+
     let mut table = Table::new();
     table
         .load_preset(UTF8_FULL)
         .apply_modifier(UTF8_ROUND_CORNERS)
-        .set_header(vec!["排名", "IP 地址", "延迟", "丢包率", "速度", "综合分"]);
+        .set_header(header);
 
     for (i, r) in display.iter().enumerate() {
         let rank = format!("#{}", i + 1);
-        let ms = r.latency.as_secs_f64() * 1000.0;
-        let latency_str = format!("{:.1} ms", ms);
-        let loss_str = format!("{:.0}%", r.loss_rate * 100.0);
-        let speed_str = format_speed(r.speed_bps);
+        let loss_str = format!("{:.0}%", r.ping.loss_rate * 100.0);
         let score_str = format!("{:.2}", r.score);
 
-        let loss_color = if r.loss_rate == 0.0 {
+        let loss_color = if r.ping.loss_rate == 0.0 {
             Color::Green
         } else {
             Color::Yellow
         };
 
-        table.add_row(vec![
-            Cell::new(rank),
-            Cell::new(r.ip.to_string()),
-            Cell::new(latency_str).fg(latency_color(ms)),
-            Cell::new(loss_str).fg(loss_color),
-            Cell::new(speed_str).fg(speed_color(r.speed_bps)),
-            Cell::new(score_str),
-        ]);
+        let mut row = vec![Cell::new(rank), Cell::new(r.ping.ip.to_string())];
+        if show_interface {
+            row.push(Cell::new(r.ping.interface.as_deref().unwrap_or("-")));
+        }
+        if show_port {
+            row.push(Cell::new(r.ping.port));
+        }
+        if show_colo {
+            row.push(Cell::new(format_colo(r.ping.colo.as_deref())));
+        }
+        let stats = &r.ping.stats;
+        for latency in [
+            stats.metric(metric),
+            stats.min,
+            stats.max,
+            stats.median,
+            stats.p95,
+        ] {
+            let ms = latency.as_secs_f64() * 1000.0;
+            row.push(Cell::new(format!("{:.1} ms", ms)).fg(latency_color(ms)));
+        }
+        for spread in [stats.stddev, stats.jitter] {
+            row.push(Cell::new(format!("{} ms", format_ms(Some(spread), ""))));
+        }
+        if show_http {
+            for phase in [r.ping.tls_handshake, r.ping.ttfb] {
+                row.push(Cell::new(phase.map_or("-".to_string(), |d| {
+                    format!("{} ms", format_ms(Some(d), ""))
+                })));
+            }
+        }
+        if show_quic {
+            row.push(Cell::new(format!(
+                "{} ms",
+                format_ms(r.ping.quic_handshake, "-")
+            )));
+            row.push(Cell::new(
+                r.ping
+                    .quic_success_rate
+                    .map_or("-".to_string(), |rate| format!("{:.0}%", rate * 100.0)),
+            ));
+        }
+        row.push(Cell::new(loss_str).fg(loss_color));
+        for bps in [
+            r.throughput.avg_bps(),
+            r.throughput.steady_bps(),
+            r.throughput.peak_bps(),
+        ] {
+            row.push(Cell::new(format_speed(bps)).fg(speed_color(bps)));
+        }
+        if show_stream {
+            row.push(match r.stream_bps {
+                Some(bps) => Cell::new(format_speed(bps)).fg(speed_color(bps)),
+                None => Cell::new("-"),
+            });
+        }
+        if show_upload {
+            row.push(match r.upload_bps {
+                Some(bps) => Cell::new(format_speed(bps)).fg(speed_color(bps)),
+                None => Cell::new("-"),
+            });
+        }
+        row.push(Cell::new(score_str));
+        table.add_row(row);
     }
 
     println!("\n{table}\n");
@@ -80,27 +213,148 @@ pub fn print_results(results: &[ScoredResult], count: usize) {
         println!(
             "{}  {}",
             "最优 IP:".green().bold(),
-            best.ip.to_string().white().bold()
+            best.ping.ip.to_string().white().bold()
         );
+        if show_port {
+            println!("{}  {}", "最优端口:".green().bold(), best.ping.port);
+        }
     }
 }
 
-pub fn write_csv(results: &[ScoredResult], path: &str) -> Result<()> {
+/// "延迟(ms)" 列为 `--latency-metric` 选择的指标, 与排序和筛选一致
+pub fn write_csv(
+    results: &[ScoredResult],
+    path: &str,
+    seed: u64,
+    metric: LatencyMetric,
+) -> Result<()> {
     let mut wtr = csv::Writer::from_path(path)?;
-    wtr.write_record(["IP", "延迟(ms)", "丢包率(%)", "速度(MB/s)", "综合分"])?;
+    wtr.write_record([
+        "IP",
+        "延迟(ms)",
+        "丢包率(%)",
+        "速度(MB/s)",
+        "综合分",
+        "种子",
+        "TCP连接(ms)",
+        "TLS握手(ms)",
+        "首字节(ms)",
+        "节点",
+        "城市",
+        "国家",
+        "最小(ms)",
+        "最大(ms)",
+        "中位数(ms)",
+        "P95(ms)",
+        "标准差(ms)",
+        "抖动(ms)",
+        "样本(ms)",
+        "提前终止",
+        "QUIC握手(ms)",
+        "QUIC成功率(%)",
+        "上传(MB/s)",
+        "单连接(MB/s)",
+        "稳定速度(MB/s)",
+        "峰值速度(MB/s)",
+        "测速首字节(ms)",
+        "吞吐曲线(MB/s)",
+        "端口",
+        "网卡",
+    ])?;
 
     for r in results {
-        let ms = r.latency.as_secs_f64() * 1000.0;
-        let mbps = r.speed_bps / 1_048_576.0;
+        let ms = r.ping.stats.metric(metric).as_secs_f64() * 1000.0;
+        let mbps = r.throughput.avg_bps() / 1_048_576.0;
+        let info = r.ping.colo.as_deref().and_then(colo::lookup);
         wtr.write_record([
-            r.ip.to_string(),
+            r.ping.ip.to_string(),
             format!("{:.1}", ms),
-            format!("{:.0}", r.loss_rate * 100.0),
+            format!("{:.0}", r.ping.loss_rate * 100.0),
             format!("{:.2}", mbps),
             format!("{:.4}", r.score),
+            seed.to_string(),
+            format_ms(r.ping.tcp_connect, ""),
+            format_ms(r.ping.tls_handshake, ""),
+            format_ms(r.ping.ttfb, ""),
+            r.ping.colo.clone().unwrap_or_default(),
+            info.map(|i| i.city).unwrap_or_default().to_string(),
+            info.map(|i| i.country).unwrap_or_default().to_string(),
+            format_ms(Some(r.ping.stats.min), ""),
+            format_ms(Some(r.ping.stats.max), ""),
+            format_ms(Some(r.ping.stats.median), ""),
+            format_ms(Some(r.ping.stats.p95), ""),
+            format_ms(Some(r.ping.stats.stddev), ""),
+            format_ms(Some(r.ping.stats.jitter), ""),
+            r.ping
+                .samples
+                .iter()
+                .map(|&s| format_ms(Some(s), ""))
+                .collect::<Vec<_>>()
+                .join(";"),
+            r.ping.aborted.to_string(),
+            format_ms(r.ping.quic_handshake, ""),
+            r.ping
+                .quic_success_rate
+                .map(|rate| format!("{:.0}", rate * 100.0))
+                .unwrap_or_default(),
+            r.upload_bps
+                .map(|bps| format!("{:.2}", bps / 1_048_576.0))
+                .unwrap_or_default(),
+            r.stream_bps
+                .map(|bps| format!("{:.2}", bps / 1_048_576.0))
+                .unwrap_or_default(),
+            format!("{:.2}", r.throughput.steady_bps() / 1_048_576.0),
+            format!("{:.2}", r.throughput.peak_bps() / 1_048_576.0),
+            format_ms(Some(r.throughput.ttfb), ""),
+            r.throughput
+                .curve
+                .iter()
+                .map(|bps| format!("{:.2}", bps / 1_048_576.0))
+                .collect::<Vec<_>>()
+                .join(";"),
+            r.ping.port.to_string(),
+            r.ping.interface.clone().unwrap_or_default(),
         ])?;
     }
 
     wtr.flush()?;
     Ok(())
 }
+
+/// 以 `--ip-file` 可直接读取的格式输出 IP 段, 来源信息写在注释行中
+pub fn print_range_lists(lists: &[RangeList]) {
+    for list in lists {
+        println!(
+            "# {}: {} 个地址段, 来源: {} ({})",
+            list.name,
+            list.ranges.len(),
+            list.origin,
+            list.location
+        );
+        for net in &list.ranges {
+            println!("{}", net);
+        }
+    }
+}
+
+/// 对比域名解析得到的 IP 与扫描得到的 IP 的最佳延迟 (按 `--latency-metric`)
+pub fn print_dns_baseline(results: &[PingResult], domain_ips: &[IpAddr], metric: LatencyMetric) {
+    let describe = |r: Option<&PingResult>| match r {
+        Some(r) => format!(
+            "{:.1} ms ({})",
+            r.stats.metric(metric).as_secs_f64() * 1000.0,
+            r.ip
+        ),
+        None => "无可用 IP".to_string(),
+    };
+
+    // 结果已按延迟排序, 第一个即为最佳
+    let best_dns = results.iter().find(|r| domain_ips.contains(&r.ip));
+    let best_scan = results.iter().find(|r| !domain_ips.contains(&r.ip));
+
+    println!(
+        "DNS 解析基线: 域名解析 IP 最佳 {}, 扫描 IP 最佳 {}",
+        describe(best_dns),
+        describe(best_scan)
+    );
+}
@@ -1,105 +1,418 @@
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::Result;
 use indicatif::{ProgressBar, ProgressStyle};
-use tokio::net::TcpStream;
-use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
-use crate::config::Config;
+use crate::bind::Binding;
+use crate::concurrency::{self, Concurrency, Outcome};
+use crate::config::{Config, LatencyMetric, Probe};
+use crate::icmp::{self, IcmpPinger};
+use crate::quic::QuicClient;
+use crate::rate::RateLimiter;
+use crate::stats::LatencyStats;
+use crate::trace::{self, HttpOutcome};
 
 #[derive(Debug, Clone)]
 pub struct PingResult {
     pub ip: IpAddr,
+    /// 多端口探测时为该 IP 表现最好的端口, 速度测试也使用该端口
+    pub port: u16,
+    /// 指定 --interface 时完成测试的网卡
+    pub interface: Option<String>,
     pub avg_latency: Duration,
     pub loss_rate: f64,
+    /// 是否因连续失败提前结束测试 (未执行的尝试计入丢包)
+    pub aborted: bool,
+    /// 按测试顺序保存的全部成功样本
+    pub samples: Vec<Duration>,
+    pub stats: LatencyStats,
+    /// HTTP 探测的 TCP 连接、TLS 握手和首字节平均耗时 (TCP 探测时为 None)
+    pub tcp_connect: Option<Duration>,
+    pub tls_handshake: Option<Duration>,
+    pub ttfb: Option<Duration>,
+    /// QUIC 探测的平均握手耗时和握手成功率 (其他探测方式为 None)
+    pub quic_handshake: Option<Duration>,
+    pub quic_success_rate: Option<f64>,
+    /// Cloudflare 节点 IATA 代码, 来自 trace 的 `colo=` 或速度测试的 `CF-RAY`
+    pub colo: Option<String>,
 }
 
-async fn tcp_ping(ip: IpAddr, port: u16, timeout: Duration) -> Option<Duration> {
-    let addr = SocketAddr::new(ip, port);
-    let start = Instant::now();
-    match tokio::time::timeout(timeout, TcpStream::connect(addr)).await {
-        Ok(Ok(_stream)) => Some(start.elapsed()),
-        _ => None,
+/// 本机资源错误时同一次尝试最多重试这么多次, 仍失败则跳过该次尝试
+const MAX_LOCAL_RETRIES: usize = 5;
+
+async fn tcp_ping(
+    binding: &Binding,
+    ip: IpAddr,
+    port: u16,
+    timeout: Duration,
+) -> (Outcome, Option<Duration>) {
+    match tokio::time::timeout(timeout, binding.connect(SocketAddr::new(ip, port))).await {
+        Ok(Ok(connection)) => (Outcome::Reply, Some(connection.connect)),
+        Ok(Err(e)) if concurrency::is_local_error(&e) => (Outcome::Local, None),
+        _ => (Outcome::Lost, None),
     }
 }
 
 pub async fn test_latency(ips: &[IpAddr], config: &Config) -> Result<Vec<PingResult>> {
-    let total = ips.len();
+    let probe = config.probe;
+    // ICMP 没有端口的概念, 只测试一次
+    let ports = match probe {
+        Probe::Icmp => config.probe_ports()?.into_iter().take(1).collect(),
+        _ => config.probe_ports()?,
+    };
+    let targets: Vec<(IpAddr, u16)> = ips
+        .iter()
+        .flat_map(|&ip| ports.iter().map(move |&port| (ip, port)))
+        .collect();
+
+    let total = targets.len();
     let pb = ProgressBar::new(total as u64);
     pb.set_style(
         ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.green/white} {pos}/{len} 延迟测试中...")
+            .template("[{elapsed_precise}] {bar:40.green/white} {pos}/{len} 延迟测试中...\n{msg}")
             .unwrap()
             .progress_chars("=> "),
     );
 
-    let semaphore = Arc::new(Semaphore::new(config.threads));
+    let concurrency = Concurrency::new(config.threads);
     let timeout = Duration::from_millis(config.timeout_ms);
     let latency_limit = Duration::from_millis(config.latency_limit_ms);
     let ping_times = config.ping_times;
-    let port = config.port;
+    let latency_metric = config.latency_metric;
+    let trim_outliers = config.trim_outliers;
+    let max_loss = config.max_loss;
+    let abort_after = config.abort_after.filter(|&n| n > 0);
+    let colo_filter = Arc::new(config.colo_filter());
+    // TCP 探测时只有指定了节点过滤才需要额外请求 trace 确定节点
+    let connector = if probe == Probe::Http || !colo_filter.is_empty() {
+        Some(trace::tls_connector()?)
+    } else {
+        None
+    };
+    let icmp_kind = match probe {
+        Probe::Icmp => Some(icmp::detect_socket_kind()?),
+        _ => None,
+    };
+    let sni: Arc<str> = config.tls_server_name().into();
+    let host: Arc<str> = config.request_host().into();
+    let limiter = config.rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+    let probe_gap = Duration::from_millis(config.probe_gap_ms);
+    let binding = Arc::new(Binding::from_config(config));
+    let quic = match probe {
+        Probe::Quic => Some(Arc::new(QuicClient::new(&binding)?)),
+        _ => None,
+    };
+    let enough = config.enough.filter(|&n| n > 0);
     let pb = Arc::new(pb);
+    // 提前终止的 IP 几乎都会因丢包超限被淘汰, 单独统计以便在汇总中体现
+    let aborted_ips = Arc::new(Mutex::new(HashSet::new()));
 
-    let mut handles = Vec::with_capacity(total);
+    let mut tasks = JoinSet::new();
 
-    for &ip in ips {
-        let sem = semaphore.clone();
+    for (ip, port) in targets {
+        let concurrency = concurrency.clone();
         let pb = pb.clone();
+        let connector = connector.clone();
+        let sni = sni.clone();
+        let binding = binding.clone();
+        let quic = quic.clone();
+        let host = host.clone();
+        let colo_filter = colo_filter.clone();
+        let limiter = limiter.clone();
+        let aborted_ips = aborted_ips.clone();
 
-        let handle = tokio::spawn(async move {
-            let _permit = sem.acquire().await.unwrap();
+        tasks.spawn(async move {
+            let mut permit = Some(concurrency.acquire().await);
 
             let mut successes = Vec::new();
+            let mut timings = Vec::new();
+            let mut colo = None;
             let mut failures = 0usize;
+            // 每个 IP 独立的 ICMP socket, 序列号随尝试递增; 创建失败时在下次尝试重新创建
+            let mut pinger: Option<IcmpPinger> = None;
+
+            let mut consecutive_failures = 0usize;
+            let mut aborted = false;
+            let mut next_attempt = tokio::time::Instant::now();
+            // 因本机资源错误跳过的尝试, 不计入丢包率的分母
+            let mut skipped = 0usize;
+            let mut local_retries = 0usize;
+            let mut attempt = 0usize;
+
+            while attempt < ping_times {
+                // 同一 IP 相邻两次尝试至少间隔 probe_gap, 等待期间归还并发许可,
+                // 让其他 IP 的探测使用这个并发槽
+                if tokio::time::Instant::now() < next_attempt {
+                    permit = None;
+                    tokio::time::sleep_until(next_attempt).await;
+                }
+                if permit.is_none() {
+                    permit = Some(concurrency.acquire().await);
+                }
+                if let Some(limiter) = &limiter {
+                    limiter.acquire().await;
+                }
+                next_attempt = tokio::time::Instant::now() + probe_gap;
+
+                let (outcome, latency) = match (probe, &connector, icmp_kind) {
+                    (Probe::Icmp, _, Some(kind)) => {
+                        if pinger.is_none() {
+                            pinger = match IcmpPinger::new(ip, kind, &binding) {
+                                Ok(created) => Some(created),
+                                Err(e) if concurrency::is_local_error(&e) => None,
+                                Err(_) => {
+                                    pb.inc(1);
+                                    return None;
+                                }
+                            };
+                        }
+                        match pinger.as_mut() {
+                            Some(pinger) => match pinger.ping(timeout).await {
+                                Ok(Some(latency)) => (Outcome::Reply, Some(latency)),
+                                Ok(None) => (Outcome::Lost, None),
+                                Err(e) if concurrency::is_local_error(&e) => (Outcome::Local, None),
+                                Err(_) => (Outcome::Lost, None),
+                            },
+                            None => (Outcome::Local, None),
+                        }
+                    }
+                    (Probe::Quic, _, _) => match &quic {
+                        Some(quic) => match quic.handshake(ip, port, &sni, timeout).await {
+                            Some(latency) => (Outcome::Reply, Some(latency)),
+                            None => (Outcome::Lost, None),
+                        },
+                        None => (Outcome::Lost, None),
+                    },
+                    (Probe::Http, Some(connector), _) => {
+                        match trace::http_ping(connector, &binding, ip, port, &sni, &host, timeout)
+                            .await
+                        {
+                            HttpOutcome::Ok(timing, trace_colo) => {
+                                timings.push(timing);
+                                colo = trace_colo.or(colo);
+                                (Outcome::Reply, Some(timing.total()))
+                            }
+                            HttpOutcome::Failed => (Outcome::Lost, None),
+                            HttpOutcome::Local => (Outcome::Local, None),
+                            // 不是 Cloudflare 节点 (或被中间设备劫持), 直接淘汰
+                            HttpOutcome::Invalid => {
+                                pb.inc(1);
+                                return None;
+                            }
+                        }
+                    }
+                    _ => tcp_ping(&binding, ip, port, timeout).await,
+                };
 
-            for _ in 0..ping_times {
-                match tcp_ping(ip, port, timeout).await {
-                    Some(latency) => successes.push(latency),
-                    None => failures += 1,
+                concurrency.record(outcome);
+
+                match (outcome, latency) {
+                    // 本机资源不足, 退避后重试同一次尝试, 不计入丢包
+                    (Outcome::Local, _) => {
+                        local_retries += 1;
+                        if local_retries < MAX_LOCAL_RETRIES {
+                            next_attempt = tokio::time::Instant::now()
+                                + Duration::from_millis(50 * local_retries as u64);
+                            continue;
+                        }
+                        skipped += 1;
+                    }
+                    (_, Some(latency)) => {
+                        successes.push(latency);
+                        consecutive_failures = 0;
+                    }
+                    (_, None) => {
+                        failures += 1;
+                        consecutive_failures += 1;
+
+                        // 连续失败达到上限, 或丢包率已注定超限时提前结束
+                        // 本机原因跳过的尝试不计入分母
+                        let hopeless =
+                            failures as f64 * 100.0 > max_loss * (ping_times - skipped) as f64;
+                        if hopeless || abort_after.is_some_and(|n| consecutive_failures >= n) {
+                            // 剩余未执行的尝试按丢包计入, 最后一次失败时没有可省略的尝试
+                            if attempt + 1 < ping_times {
+                                failures += ping_times - attempt - 1;
+                                aborted = true;
+                            }
+                            break;
+                        }
+                    }
                 }
+                attempt += 1;
+                local_retries = 0;
+            }
+            if aborted {
+                aborted_ips.lock().unwrap().insert(ip);
             }
 
             pb.inc(1);
 
-            let total_attempts = ping_times;
-            let loss_rate = failures as f64 / total_attempts as f64;
-
-            if successes.is_empty() {
+            let total_attempts = ping_times - skipped;
+            if successes.is_empty() || total_attempts == 0 {
                 return None;
             }
+            let loss_rate = failures as f64 / total_attempts as f64;
 
-            // 丢包率超过 50% 视为不可用
-            if loss_rate > 0.5 {
+            if loss_rate * 100.0 > max_loss {
                 return None;
             }
 
-            let avg = successes.iter().sum::<Duration>() / successes.len() as u32;
+            let stats = LatencyStats::from_samples(&successes, trim_outliers)?;
 
-            if avg > latency_limit {
+            if stats.metric(latency_metric) > latency_limit {
                 return None;
             }
 
+            if !colo_filter.is_empty() {
+                if colo.is_none()
+                    && let Some(connector) = &connector
+                {
+                    if let Some(limiter) = &limiter {
+                        limiter.acquire().await;
+                    }
+                    if let HttpOutcome::Ok(_, trace_colo) =
+                        trace::http_ping(connector, &binding, ip, port, &sni, &host, timeout).await
+                    {
+                        colo = trace_colo;
+                    }
+                }
+                if !colo_filter.allows(colo.as_deref()) {
+                    return None;
+                }
+            }
+
+            let mean = |values: Vec<Duration>| {
+                (!values.is_empty()).then(|| values.iter().sum::<Duration>() / values.len() as u32)
+            };
+
             Some(PingResult {
                 ip,
-                avg_latency: avg,
+                port,
+                interface: binding.interface.clone(),
+                avg_latency: stats.avg,
                 loss_rate,
+                aborted,
+                samples: successes,
+                stats,
+                tcp_connect: mean(timings.iter().map(|t| t.tcp_connect).collect()),
+                tls_handshake: mean(timings.iter().filter_map(|t| t.tls_handshake).collect()),
+                ttfb: mean(timings.iter().map(|t| t.ttfb).collect()),
+                quic_handshake: (probe == Probe::Quic).then_some(stats.avg),
+                quic_success_rate: (probe == Probe::Quic).then_some(1.0 - loss_rate),
+                colo,
             })
         });
-
-        handles.push(handle);
     }
 
-    let mut results = Vec::new();
-    for handle in handles {
-        if let Ok(Some(result)) = handle.await {
-            results.push(result);
+    // 按完成顺序收集, 同一 IP 在多个端口上的结果只保留延迟最低的一个
+    let rank = |r: &PingResult| (r.stats.metric(latency_metric), r.port);
+    let mut best: HashMap<IpAddr, PingResult> = HashMap::new();
+    let mut live = LiveTop::new(config.count, latency_metric, ports.len() > 1);
+    while let Some(joined) = tasks.join_next().await {
+        let Ok(Some(result)) = joined else {
+            continue;
+        };
+        if best
+            .get(&result.ip)
+            .is_some_and(|current| rank(current) <= rank(&result))
+        {
+            continue;
+        }
+        if live.offer(&result) {
+            pb.set_message(live.render());
+        }
+        best.insert(result.ip, result);
+
+        if enough.is_some_and(|n| best.len() >= n) {
+            // 丢弃 JoinSet 前先取消尚未完成的探测
+            tasks.abort_all();
+            break;
         }
     }
 
     pb.finish_and_clear();
+    if let Some(limit) = concurrency.limit() {
+        println!("自适应并发: 结束时为 {}", limit);
+    }
+    let aborted = aborted_ips.lock().unwrap().len();
+    if aborted > 0 {
+        println!(
+            "提前终止: {} 个 IP 因连续失败或丢包率注定超过 --max-loss 提前结束测试",
+            aborted
+        );
+    }
+    if let Some(n) = enough
+        && best.len() >= n
+        && !tasks.is_empty()
+    {
+        println!("已找到 {} 个满足条件的 IP, 提前结束延迟测试", best.len());
+    }
+
+    let mut results: Vec<PingResult> = best.into_values().collect();
 
-    results.sort_by(|a, b| a.avg_latency.cmp(&b.avg_latency));
+    results.sort_by_key(|r| (r.stats.metric(latency_metric), r.ip));
     Ok(results)
 }
+
+/// 进度条下方实时显示的当前延迟最低的 IP
+struct LiveTop {
+    size: usize,
+    metric: LatencyMetric,
+    show_port: bool,
+    entries: Vec<(Duration, IpAddr, u16)>,
+}
+
+impl LiveTop {
+    fn new(size: usize, metric: LatencyMetric, show_port: bool) -> Self {
+        Self {
+            size,
+            metric,
+            show_port,
+            entries: Vec::with_capacity(size + 1),
+        }
+    }
+
+    /// 记录某个 IP 更优的结果, 返回显示内容是否发生变化
+    fn offer(&mut self, result: &PingResult) -> bool {
+        let latency = result.stats.metric(self.metric);
+        let previous = self.entries.len();
+        self.entries.retain(|&(_, ip, _)| ip != result.ip);
+        let replaced = self.entries.len() != previous;
+
+        let pos = self
+            .entries
+            .partition_point(|&(l, ip, _)| (l, ip) < (latency, result.ip));
+        if pos >= self.size {
+            return replaced;
+        }
+        self.entries.insert(pos, (latency, result.ip, result.port));
+        self.entries.truncate(self.size);
+        true
+    }
+
+    fn render(&self) -> String {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, &(latency, ip, port))| {
+                let addr = if self.show_port {
+                    SocketAddr::new(ip, port).to_string()
+                } else {
+                    ip.to_string()
+                };
+                format!(
+                    "  #{:<3} {:<41} {:>7.1} ms",
+                    i + 1,
+                    addr,
+                    latency.as_secs_f64() * 1000.0
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
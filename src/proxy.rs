@@ -0,0 +1,395 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::bind::Binding;
+use crate::status;
+
+/// 上游代理类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+/// `--via` 指定的上游代理, 所有到候选 IP 的 TCP 连接经由它建立隧道
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub addr: SocketAddr,
+    pub auth: Option<(String, String)>,
+}
+
+/// 经代理建立的隧道
+pub struct Tunnel {
+    pub stream: TcpStream,
+    /// 扣除本机到代理往返后, 代理连接目标的估算耗时
+    pub connect: Duration,
+    /// 本机到代理的往返耗时估算, 隧道内的每个往返都会额外经过这一段
+    pub proxy_rtt: Duration,
+}
+
+/// 解析 `socks5://[user:pass@]host:port` 或 `http://[user:pass@]host:port`
+pub fn parse_proxy(spec: &str) -> Result<Proxy, String> {
+    let url = reqwest::Url::parse(spec).map_err(|e| format!("无效的代理地址 {}: {}", spec, e))?;
+    let kind = match url.scheme() {
+        "socks5" => ProxyKind::Socks5,
+        "http" => ProxyKind::HttpConnect,
+        other => return Err(format!("不支持的代理协议: {} (可用 socks5、http)", other)),
+    };
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("代理地址缺少主机: {}", spec))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(1080);
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("无法解析代理地址 {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("无法解析代理地址 {}", host))?;
+    let auth = (!url.username().is_empty()).then(|| {
+        (
+            url.username().to_string(),
+            url.password().unwrap_or_default().to_string(),
+        )
+    });
+
+    Ok(Proxy { kind, addr, auth })
+}
+
+impl Proxy {
+    /// 连接代理并请求其连接 `target`
+    ///
+    /// 本机到代理的往返以 TCP 握手 (SOCKS5 还有协商阶段) 的最小耗时估算,
+    /// 从代理回复 CONNECT 的耗时中扣除, 近似得到代理到目标的连接耗时。
+    pub async fn tunnel(&self, binding: &Binding, target: SocketAddr) -> io::Result<Tunnel> {
+        let start = Instant::now();
+        let mut stream = binding.connect_direct(self.addr).await?;
+        let mut proxy_rtt = start.elapsed();
+        stream.set_nodelay(true)?;
+
+        let elapsed = match self.kind {
+            ProxyKind::Socks5 => {
+                let start = Instant::now();
+                self.socks5_handshake(&mut stream).await?;
+                proxy_rtt = proxy_rtt.min(start.elapsed());
+
+                let start = Instant::now();
+                socks5_connect(&mut stream, target).await?;
+                start.elapsed()
+            }
+            ProxyKind::HttpConnect => {
+                let start = Instant::now();
+                self.http_connect(&mut stream, target).await?;
+                start.elapsed()
+            }
+        };
+
+        Ok(Tunnel {
+            stream,
+            connect: elapsed.saturating_sub(proxy_rtt),
+            proxy_rtt,
+        })
+    }
+
+    async fn socks5_handshake(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let greeting: &[u8] = match self.auth {
+            Some(_) => &[5, 2, 0, 2],
+            None => &[5, 1, 0],
+        };
+        stream.write_all(greeting).await?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        match (reply, &self.auth) {
+            ([5, 0], _) => Ok(()),
+            ([5, 2], Some((user, pass))) => {
+                let mut request = vec![1, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(pass.len() as u8);
+                request.extend_from_slice(pass.as_bytes());
+                stream.write_all(&request).await?;
+
+                let mut reply = [0u8; 2];
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(proxy_error("SOCKS5 认证失败"));
+                }
+                Ok(())
+            }
+            _ => Err(proxy_error("SOCKS5 代理不接受可用的认证方式")),
+        }
+    }
+
+    async fn http_connect(&self, stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((user, pass)) = &self.auth {
+            let token = STANDARD.encode(format!("{}:{}", user, pass));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // 逐字节读取, 避免把隧道内的数据读进缓冲
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > 8192 {
+                return Err(proxy_error("HTTP 代理响应头过长"));
+            }
+            stream.read_exact(&mut byte).await?;
+            head.push(byte[0]);
+        }
+
+        if !status::is_success(&head) {
+            return Err(proxy_error("HTTP 代理拒绝了 CONNECT 请求"));
+        }
+        Ok(())
+    }
+}
+
+async fn socks5_connect(stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+    let mut request = vec![5, 1, 0];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error("SOCKS5 代理连接目标失败"));
+    }
+
+    // 读掉回复中的绑定地址和端口
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(proxy_error("SOCKS5 回复的地址类型无效")),
+    };
+    let mut rest = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut rest).await?;
+    Ok(())
+}
+
+fn proxy_error(message: &str) -> io::Error {
+    io::Error::other(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const USER: &str = "user";
+    const PASS: &str = "secret";
+
+    /// 目标服务: 读到 `ping` 后回复 `pong`
+    async fn echo_target() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4];
+                    if stream.read_exact(&mut buf).await.is_ok() && &buf == b"ping" {
+                        let _ = stream.write_all(b"pong").await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// 最小的 SOCKS5 服务, `auth` 为 `Some` 时要求用户名密码认证
+    async fn socks5_server(auth: Option<(&'static str, &'static str)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = serve_socks5(stream, auth).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn serve_socks5(mut stream: TcpStream, auth: Option<(&str, &str)>) -> io::Result<()> {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await?;
+        let mut methods = vec![0u8; head[1] as usize];
+        stream.read_exact(&mut methods).await?;
+
+        let method = if auth.is_some() { 2 } else { 0 };
+        if !methods.contains(&method) {
+            stream.write_all(&[5, 0xff]).await?;
+            return Ok(());
+        }
+        stream.write_all(&[5, method]).await?;
+
+        if let Some((user, pass)) = auth {
+            stream.read_u8().await?;
+            let mut got_user = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut got_user).await?;
+            let mut got_pass = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut got_pass).await?;
+            if got_user != user.as_bytes() || got_pass != pass.as_bytes() {
+                stream.write_all(&[1, 1]).await?;
+                return Ok(());
+            }
+            stream.write_all(&[1, 0]).await?;
+        }
+
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).await?;
+        let ip = match request[3] {
+            1 => {
+                let mut octets = [0u8; 4];
+                stream.read_exact(&mut octets).await?;
+                IpAddr::from(octets)
+            }
+            _ => {
+                let mut octets = [0u8; 16];
+                stream.read_exact(&mut octets).await?;
+                IpAddr::from(octets)
+            }
+        };
+        let port = stream.read_u16().await?;
+
+        let mut upstream = TcpStream::connect(SocketAddr::new(ip, port)).await?;
+        stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+        Ok(())
+    }
+
+    /// 最小的 HTTP CONNECT 代理, `auth` 为 `Some` 时要求 Basic 认证
+    async fn http_server(auth: Option<(&'static str, &'static str)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = serve_http(stream, auth).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn serve_http(mut stream: TcpStream, auth: Option<(&str, &str)>) -> io::Result<()> {
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).await?;
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head).to_string();
+
+        if let Some((user, pass)) = auth {
+            let expected = format!(
+                "Proxy-Authorization: Basic {}",
+                STANDARD.encode(format!("{}:{}", user, pass))
+            );
+            if !head.lines().any(|line| line == expected) {
+                stream
+                    .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        let target: SocketAddr = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|target| target.parse().ok())
+            .ok_or_else(|| proxy_error("bad CONNECT"))?;
+        let mut upstream = TcpStream::connect(target).await?;
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+        Ok(())
+    }
+
+    fn proxy(kind: ProxyKind, addr: SocketAddr, auth: Option<(&str, &str)>) -> Proxy {
+        Proxy {
+            kind,
+            addr,
+            auth: auth.map(|(user, pass)| (user.to_string(), pass.to_string())),
+        }
+    }
+
+    async fn ping_through(proxy: &Proxy, target: SocketAddr) -> io::Result<[u8; 4]> {
+        let mut tunnel = proxy.tunnel(&Binding::default(), target).await?;
+        tunnel.stream.write_all(b"ping").await?;
+        let mut reply = [0u8; 4];
+        tunnel.stream.read_exact(&mut reply).await?;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn socks5_tunnel_without_auth() {
+        let target = echo_target().await;
+        let proxy = proxy(ProxyKind::Socks5, socks5_server(None).await, None);
+        assert_eq!(&ping_through(&proxy, target).await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn socks5_tunnel_with_auth() {
+        let target = echo_target().await;
+        let server = socks5_server(Some((USER, PASS))).await;
+
+        let proxy = proxy(ProxyKind::Socks5, server, Some((USER, PASS)));
+        assert_eq!(&ping_through(&proxy, target).await.unwrap(), b"pong");
+
+        let wrong = Proxy {
+            auth: Some((USER.to_string(), "wrong".to_string())),
+            ..proxy.clone()
+        };
+        assert!(ping_through(&wrong, target).await.is_err());
+
+        let anonymous = Proxy {
+            auth: None,
+            ..proxy
+        };
+        assert!(ping_through(&anonymous, target).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn http_tunnel_without_auth() {
+        let target = echo_target().await;
+        let proxy = proxy(ProxyKind::HttpConnect, http_server(None).await, None);
+        assert_eq!(&ping_through(&proxy, target).await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn http_tunnel_with_auth() {
+        let target = echo_target().await;
+        let server = http_server(Some((USER, PASS))).await;
+
+        let proxy = proxy(ProxyKind::HttpConnect, server, Some((USER, PASS)));
+        assert_eq!(&ping_through(&proxy, target).await.unwrap(), b"pong");
+
+        let anonymous = Proxy {
+            auth: None,
+            ..proxy
+        };
+        assert!(ping_through(&anonymous, target).await.is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes};
+use http::{Method, Request, StatusCode};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Endpoint, EndpointConfig, TokioRuntime};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::bind::Binding;
+use crate::colo;
+use crate::throughput::{Sampler, Throughput};
+
+const ALPN_H3: &[u8] = b"h3";
+
+/// QUIC 握手探测和 HTTP/3 下载共用的客户端
+///
+/// 每个地址族一个 UDP socket, 所有连接复用, 不随候选数量占用文件描述符。
+pub struct QuicClient {
+    v4: Option<Endpoint>,
+    v6: Option<Endpoint>,
+    config: ClientConfig,
+}
+
+impl QuicClient {
+    pub fn new(binding: &Binding) -> Result<Self> {
+        let roots =
+            rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN_H3.to_vec()];
+        let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+
+        // 指定源地址时只能连接同一地址族的候选
+        let v4 = match binding.address {
+            Some(IpAddr::V6(_)) => None,
+            _ => Some(endpoint(binding, IpAddr::V4(Ipv4Addr::UNSPECIFIED))?),
+        };
+        let v6 = match binding.address {
+            Some(IpAddr::V4(_)) => None,
+            // 没有 IPv6 的主机上创建失败不影响 IPv4
+            _ => endpoint(binding, IpAddr::V6(Ipv6Addr::UNSPECIFIED)).ok(),
+        };
+
+        Ok(Self { v4, v6, config })
+    }
+
+    /// 完成一次 QUIC 握手, 返回握手耗时, 失败或超时返回 `None`
+    pub async fn handshake(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        sni: &str,
+        timeout: Duration,
+    ) -> Option<Duration> {
+        let start = Instant::now();
+        let connecting = self
+            .endpoint(ip)?
+            .connect_with(self.config.clone(), SocketAddr::new(ip, port), sni)
+            .ok()?;
+        let connection = tokio::time::timeout(timeout, connecting).await.ok()?.ok()?;
+        let elapsed = start.elapsed();
+        connection.close(0u32.into(), b"");
+        Some(elapsed)
+    }
+
+    /// 通过 HTTP/3 下载, 从建立连接前开始对收到的数据采样, 同时返回 `cf-ray` 中的节点代码
+    pub async fn download(
+        &self,
+        addr: SocketAddr,
+        sni: &str,
+        authority: &str,
+        path: &str,
+        max_duration: Duration,
+    ) -> Result<(Throughput, Option<String>)> {
+        let mut sampler = Sampler::start();
+        let mut colo = None;
+        let deadline = tokio::time::Instant::now() + max_duration;
+        let endpoint = self.endpoint(addr.ip()).context("没有可用的 UDP socket")?;
+        let connecting = endpoint.connect_with(self.config.clone(), addr, sni)?;
+        let connection = tokio::time::timeout_at(deadline, connecting).await??;
+
+        let result = tokio::time::timeout_at(
+            deadline,
+            h3_get(&connection, authority, path, &mut sampler, &mut colo),
+        )
+        .await;
+        connection.close(0u32.into(), b"");
+        // 超时只是到达测试时长, 已收到的数据照常计算
+        if let Ok(Err(e)) = result {
+            return Err(e);
+        }
+
+        Ok((sampler.finish()?, colo))
+    }
+
+    /// 通过 HTTP/3 上传 `size` 字节, 对已发送的数据采样
+    pub async fn upload(
+        &self,
+        addr: SocketAddr,
+        sni: &str,
+        authority: &str,
+        path: &str,
+        size: u64,
+        max_duration: Duration,
+    ) -> Result<Throughput> {
+        let deadline = tokio::time::Instant::now() + max_duration;
+        let endpoint = self.endpoint(addr.ip()).context("没有可用的 UDP socket")?;
+        let connecting = endpoint.connect_with(self.config.clone(), addr, sni)?;
+        let connection = tokio::time::timeout_at(deadline, connecting).await??;
+
+        // 从连接建立后开始计时, 只衡量上行吞吐
+        let mut sampler = Sampler::start();
+        let result = tokio::time::timeout_at(
+            deadline,
+            h3_post(&connection, authority, path, size, &mut sampler),
+        )
+        .await;
+        connection.close(0u32.into(), b"");
+        if let Ok(Err(e)) = result {
+            return Err(e);
+        }
+
+        sampler.finish()
+    }
+
+    fn endpoint(&self, ip: IpAddr) -> Option<&Endpoint> {
+        match ip {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+}
+
+fn endpoint(binding: &Binding, unspecified: IpAddr) -> Result<Endpoint> {
+    let domain = match unspecified {
+        IpAddr::V4(_) => Domain::IPV4,
+        IpAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+    }
+    let local = binding.address.unwrap_or(unspecified);
+    binding.apply(&socket, local)?;
+    if binding.address.is_none() {
+        socket.bind(&SocketAddr::new(unspecified, 0).into())?;
+    }
+
+    let endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        None,
+        socket.into(),
+        Arc::new(TokioRuntime),
+    )?;
+    Ok(endpoint)
+}
+
+/// 通过 h3 发送 GET, 把收到的响应体字节数交给采样器
+///
+/// 节点代码在收到响应头时写入 `colo`, 读取响应体途中超时也不会丢失。
+async fn h3_get(
+    connection: &quinn::Connection,
+    authority: &str,
+    path: &str,
+    sampler: &mut Sampler,
+    colo: &mut Option<String>,
+) -> Result<()> {
+    let (driver, mut send_request) = h3_client(connection).await?;
+    let request = request(Method::GET, authority, path)?;
+    let mut stream = send_request.send_request(request).await?;
+    stream.finish().await?;
+
+    let response = stream.recv_response().await?;
+    check_status(response.status())?;
+    *colo = response
+        .headers()
+        .get("cf-ray")
+        .and_then(|v| v.to_str().ok())
+        .and_then(colo::from_cf_ray);
+    while let Some(chunk) = stream.recv_data().await? {
+        sampler.record(chunk.remaining() as u64);
+    }
+    driver.abort();
+    Ok(())
+}
+
+/// 通过 h3 发送 POST, 请求体为 `size` 字节的零, 边发送边记录已发送的字节数
+async fn h3_post(
+    connection: &quinn::Connection,
+    authority: &str,
+    path: &str,
+    size: u64,
+    sampler: &mut Sampler,
+) -> Result<()> {
+    let (driver, mut send_request) = h3_client(connection).await?;
+    let mut request = request(Method::POST, authority, path)?;
+    request
+        .headers_mut()
+        .insert(http::header::CONTENT_LENGTH, size.into());
+    let mut stream = send_request.send_request(request).await?;
+
+    let chunk = Bytes::from(vec![0u8; 64 * 1024]);
+    let mut sent_bytes = 0;
+    while sent_bytes < size {
+        let n = (size - sent_bytes).min(chunk.len() as u64);
+        stream.send_data(chunk.slice(..n as usize)).await?;
+        sampler.record(n);
+        sent_bytes += n;
+    }
+    stream.finish().await?;
+
+    // 等待响应确认服务器收完请求体
+    check_status(stream.recv_response().await?.status())?;
+    while stream.recv_data().await?.is_some() {}
+    driver.abort();
+    Ok(())
+}
+
+/// 在已建立的 QUIC 连接上创建 HTTP/3 客户端, 连接驱动在后台任务中运行
+async fn h3_client(
+    connection: &quinn::Connection,
+) -> Result<(
+    tokio::task::JoinHandle<()>,
+    h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+)> {
+    let (mut driver, send_request) =
+        h3::client::new(h3_quinn::Connection::new(connection.clone())).await?;
+    let driver = tokio::spawn(async move {
+        let _ = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+    });
+    Ok((driver, send_request))
+}
+
+fn request(method: Method, authority: &str, path: &str) -> Result<Request<()>> {
+    let request = Request::builder()
+        .method(method)
+        .uri(format!("https://{}{}", authority, path))
+        .header(http::header::USER_AGENT, "cfip")
+        .body(())?;
+    Ok(request)
+}
+
+fn check_status(status: StatusCode) -> Result<()> {
+    if !status.is_success() {
+        anyhow::bail!("速度测试请求失败: HTTP/3 状态码 {}", status.as_u16());
+    }
+    Ok(())
+}
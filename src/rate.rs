@@ -0,0 +1,100 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// 全局探测速率限制 (容量为 1 的令牌桶)
+///
+/// 每个令牌对应一个发放时刻, 相邻时刻间隔 `1 / rate`, 因此探测均匀分布而不会成批发出。
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self::with_interval(Duration::from_secs(1) / per_second.max(1))
+    }
+
+    /// 直接指定相邻令牌的间隔, 间隔为 0 时不限制
+    pub fn with_interval(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// 等待下一个可用令牌
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_are_evenly_spaced() {
+        let limiter = RateLimiter::new(4);
+        let start = Instant::now();
+        let mut slots = Vec::new();
+        for _ in 0..5 {
+            limiter.acquire().await;
+            slots.push(start.elapsed());
+        }
+        let expected: Vec<Duration> = (0..5).map(|i| Duration::from_millis(250 * i)).collect();
+        assert_eq!(slots, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_callers_share_the_rate() {
+        let limiter = std::sync::Arc::new(RateLimiter::with_interval(Duration::from_millis(100)));
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..3)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    limiter.acquire().await;
+                    start.elapsed()
+                })
+            })
+            .collect();
+
+        let mut slots = Vec::new();
+        for task in tasks {
+            slots.push(task.await.unwrap());
+        }
+        slots.sort();
+        let expected: Vec<Duration> = (0..3).map(|i| Duration::from_millis(100 * i)).collect();
+        assert_eq!(slots, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_time_does_not_accumulate_a_burst() {
+        let limiter = RateLimiter::with_interval(Duration::from_millis(100));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_interval_never_waits() {
+        let limiter = RateLimiter::with_interval(Duration::ZERO);
+        let start = Instant::now();
+        for _ in 0..100 {
+            limiter.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
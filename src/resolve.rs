@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use hickory_resolver::TokioAsyncResolver;
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfigGroup, ResolverConfig, ResolverOpts,
+};
+
+/// 解析 `--dns-server` 参数, 支持 `IP` 或 `IP:端口`
+pub fn parse_dns_server(spec: &str) -> Result<SocketAddr, String> {
+    spec.parse::<SocketAddr>()
+        .or_else(|_| spec.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("无效的 DNS 服务器地址 '{}'", spec))
+}
+
+/// 读取域名列表文件并解析为去重后的 IP 列表, 保持首次出现的顺序
+///
+/// 每行一个域名, `#` 之后为注释; AAAA 记录仅在 `include_ipv6` 时保留。
+pub async fn resolve_domain_file(
+    path: &str,
+    dns_server: Option<SocketAddr>,
+    include_ipv6: bool,
+) -> Result<Vec<IpAddr>> {
+    let body = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("读取域名文件 '{}' 失败", path))?;
+
+    let domains: Vec<&str> = body
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    // 未指定 DNS 服务器时使用系统解析器 (getaddrinfo)
+    let resolver = dns_server.map(|addr| {
+        let mut opts = ResolverOpts::default();
+        opts.ip_strategy = if include_ipv6 {
+            LookupIpStrategy::Ipv4AndIpv6
+        } else {
+            LookupIpStrategy::Ipv4Only
+        };
+        let group = NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+        TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], group), opts)
+    });
+
+    let mut seen = HashSet::new();
+    let mut ips = Vec::new();
+
+    for domain in domains {
+        let resolved = match &resolver {
+            None => tokio::net::lookup_host((domain, 0))
+                .await
+                .map(|addrs| addrs.map(|a| a.ip()).collect::<Vec<_>>())
+                .map_err(anyhow::Error::from),
+            Some(r) => r
+                .lookup_ip(domain)
+                .await
+                .map(|lookup| lookup.iter().collect::<Vec<_>>())
+                .map_err(anyhow::Error::from),
+        };
+
+        match resolved {
+            Ok(addrs) => {
+                for ip in addrs {
+                    if (include_ipv6 || ip.is_ipv4()) && seen.insert(ip) {
+                        ips.push(ip);
+                    }
+                }
+            }
+            Err(e) => eprintln!(
+                "{}",
+                format!("警告: 解析域名 '{}' 失败: {}", domain, e).yellow()
+            ),
+        }
+    }
+
+    Ok(ips)
+}
@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+use anyhow::Result;
+use colored::Colorize;
+use ipnetwork::IpNetwork;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+use crate::config::{Config, Strategy};
+use crate::filter::Filter;
+use crate::ip;
+use crate::ping::{self, PingResult};
+
+/// 第一阶段只用于给子网排序, 每个 IP 最多测试这么多次
+const PHASE_ONE_PING_TIMES: usize = 3;
+
+/// IPv6 子网无法穷举, `--subnet-samples 0` 时每个子网最多采样这么多个
+const MAX_V6_SUBNET_SAMPLES: usize = 256;
+
+/// 按配置的采样策略完成延迟测试, 返回按延迟排序的结果
+///
+/// 采样和测试顺序由 `seed` 决定, 相同种子可以重放同一组候选 IP。
+/// `spans` 为来自 `a-b` 地址段的地址块, 见 [`ip::sample_network`];
+/// `extra` 为不经采样、直接测试的候选 IP (如域名解析结果)。
+pub async fn run_latency_stage(
+    ranges: &[IpNetwork],
+    spans: &[IpNetwork],
+    extra: &[IpAddr],
+    config: &Config,
+    filter: &Filter,
+    seed: u64,
+) -> Result<Vec<PingResult>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    match config.strategy {
+        Strategy::Fixed => {
+            let mut ips = ip::sample_ips(ranges, spans, &mut rng);
+            append_unique(&mut ips, extra);
+            let ips = filter.filter_candidates(ips);
+            ping::test_latency(&ips, config).await
+        }
+        Strategy::Adaptive => adaptive_scan(ranges, spans, extra, config, filter, &mut rng).await,
+    }
+}
+
+async fn adaptive_scan(
+    ranges: &[IpNetwork],
+    spans: &[IpNetwork],
+    extra: &[IpAddr],
+    config: &Config,
+    filter: &Filter,
+    rng: &mut StdRng,
+) -> Result<Vec<PingResult>> {
+    // 第一阶段: 每个子网探测一个 IP, 记下它代表的子网
+    let buckets = ip::subnet_buckets(ranges, rng);
+    let mut probed: HashMap<IpAddr, IpNetwork> = HashMap::new();
+    let mut probes = Vec::with_capacity(buckets.len());
+    for &bucket in &buckets {
+        for ip in ip::sample_network(bucket, 1, ip::in_spans(bucket, spans), rng) {
+            if probed.insert(ip, bucket).is_none() {
+                probes.push(ip);
+            }
+        }
+    }
+    let probes = filter.filter_candidates(probes);
+
+    println!("第一阶段: 探测 {} 个子网", buckets.len());
+    let mut phase_one_config = config.clone();
+    phase_one_config.ping_times = config.ping_times.min(PHASE_ONE_PING_TIMES);
+    // 子网排序需要完整的第一阶段结果, --enough 只作用于第二阶段
+    phase_one_config.enough = None;
+    let phase_one = ping::test_latency(&probes, &phase_one_config).await?;
+
+    // 第二阶段: 在延迟最低的子网内密集采样
+    // 子网取自输入地址段, 不会超出 --ip-file 或 --include 的范围
+    let mut seen = HashSet::new();
+    let top: Vec<IpNetwork> = phase_one
+        .iter()
+        .filter_map(|r| probed.get(&r.ip).copied())
+        .filter(|bucket| seen.insert(*bucket))
+        .take(config.top_subnets)
+        .collect();
+
+    let mut candidates = Vec::new();
+    for &bucket in &top {
+        let count = match (config.subnet_samples, bucket) {
+            (0, IpNetwork::V4(_)) => usize::MAX,
+            (0, IpNetwork::V6(_)) => MAX_V6_SUBNET_SAMPLES,
+            (n, _) => n,
+        };
+        let whole = ip::in_spans(bucket, spans);
+        append_unique(
+            &mut candidates,
+            &ip::sample_network(bucket, count, whole, rng),
+        );
+    }
+    append_unique(&mut candidates, extra);
+    let candidates = filter.filter_candidates(candidates);
+
+    println!(
+        "{}",
+        format!(
+            "第一阶段完成，{} 个子网可用；第二阶段: 在前 {} 个子网中测试 {} 个 IP",
+            phase_one.len(),
+            top.len(),
+            candidates.len()
+        )
+        .green()
+    );
+    let phase_two = ping::test_latency(&candidates, config).await?;
+
+    // 合并两阶段结果, 同一 IP 以第二阶段 (测试次数更多) 为准
+    let mut merged: HashMap<IpAddr, PingResult> =
+        phase_one.into_iter().map(|r| (r.ip, r)).collect();
+    merged.extend(phase_two.into_iter().map(|r| (r.ip, r)));
+
+    let mut results: Vec<PingResult> = merged.into_values().collect();
+    results.sort_by_key(|r| (r.stats.metric(config.latency_metric), r.ip));
+    Ok(results)
+}
+
+fn append_unique(ips: &mut Vec<IpAddr>, extra: &[IpAddr]) {
+    let mut seen: HashSet<IpAddr> = ips.iter().copied().collect();
+    ips.extend(extra.iter().copied().filter(|ip| seen.insert(*ip)));
+}
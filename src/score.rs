@@ -1,18 +1,26 @@
-use std::net::IpAddr;
-use std::time::Duration;
-
+use crate::config::LatencyMetric;
+use crate::ping::PingResult;
 use crate::speed::SpeedResult;
+use crate::throughput::Throughput;
+
+/// 速度在综合分中的权重, 启用上传测试时由下载和上传分摊
+pub const SPEED_WEIGHT: f64 = 0.7;
 
 #[derive(Debug, Clone)]
 pub struct ScoredResult {
-    pub ip: IpAddr,
-    pub latency: Duration,
-    pub loss_rate: f64,
+    pub ping: PingResult,
     pub speed_bps: f64,
+    pub throughput: Throughput,
+    pub stream_bps: Option<f64>,
+    pub upload_bps: Option<f64>,
     pub score: f64,
 }
 
-pub fn calculate_scores(results: &[SpeedResult]) -> Vec<ScoredResult> {
+pub fn calculate_scores(
+    results: &[SpeedResult],
+    metric: LatencyMetric,
+    upload_weight: f64,
+) -> Vec<ScoredResult> {
     if results.is_empty() {
         return Vec::new();
     }
@@ -20,53 +28,90 @@ pub fn calculate_scores(results: &[SpeedResult]) -> Vec<ScoredResult> {
     if results.len() == 1 {
         let r = &results[0];
         return vec![ScoredResult {
-            ip: r.ip,
-            latency: r.avg_latency,
-            loss_rate: r.loss_rate,
+            ping: r.ping.clone(),
             speed_bps: r.speed_bps,
+            throughput: r.throughput.clone(),
+            stream_bps: r.stream_bps,
+            upload_bps: r.upload_bps,
             score: 1.0,
         }];
     }
 
     let latencies: Vec<f64> = results
         .iter()
-        .map(|r| r.avg_latency.as_secs_f64())
+        .map(|r| r.ping.stats.metric(metric).as_secs_f64())
+        .collect();
+    let jitters: Vec<f64> = results
+        .iter()
+        .map(|r| r.ping.stats.jitter.as_secs_f64())
         .collect();
     let speeds: Vec<f64> = results.iter().map(|r| r.speed_bps).collect();
+    // 上传失败的 IP 按 0 计
+    let uploads: Vec<f64> = results
+        .iter()
+        .map(|r| r.upload_bps.unwrap_or(0.0))
+        .collect();
+    let upload_weight = if results.iter().any(|r| r.upload_bps.is_some()) {
+        upload_weight
+    } else {
+        0.0
+    };
 
     let lat_min = latencies.iter().cloned().fold(f64::INFINITY, f64::min);
     let lat_max = latencies.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let jit_min = jitters.iter().cloned().fold(f64::INFINITY, f64::min);
+    let jit_max = jitters.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
     let spd_min = speeds.iter().cloned().fold(f64::INFINITY, f64::min);
     let spd_max = speeds.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let up_min = uploads.iter().cloned().fold(f64::INFINITY, f64::min);
+    let up_max = uploads.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
 
     let lat_range = lat_max - lat_min;
+    let jit_range = jit_max - jit_min;
     let spd_range = spd_max - spd_min;
+    let up_range = up_max - up_min;
 
     let mut scored: Vec<ScoredResult> = results
         .iter()
         .map(|r| {
-            let lat = r.avg_latency.as_secs_f64();
+            let lat = r.ping.stats.metric(metric).as_secs_f64();
+            let jit = r.ping.stats.jitter.as_secs_f64();
             let spd = r.speed_bps;
+            let up = r.upload_bps.unwrap_or(0.0);
 
-            // 归一化: 延迟越低越好, 速度越高越好
+            // 归一化: 延迟和抖动越低越好, 速度越高越好
             let lat_score = if lat_range > 0.0 {
                 1.0 - (lat - lat_min) / lat_range
             } else {
                 1.0
             };
+            let jit_score = if jit_range > 0.0 {
+                1.0 - (jit - jit_min) / jit_range
+            } else {
+                1.0
+            };
             let spd_score = if spd_range > 0.0 {
                 (spd - spd_min) / spd_range
             } else {
                 1.0
             };
+            let up_score = if up_range > 0.0 {
+                (up - up_min) / up_range
+            } else {
+                1.0
+            };
 
-            let score = lat_score * 0.3 + spd_score * 0.7;
+            let score = lat_score * 0.25
+                + jit_score * 0.05
+                + spd_score * (SPEED_WEIGHT - upload_weight)
+                + up_score * upload_weight;
 
             ScoredResult {
-                ip: r.ip,
-                latency: r.avg_latency,
-                loss_rate: r.loss_rate,
+                ping: r.ping.clone(),
                 speed_bps: r.speed_bps,
+                throughput: r.throughput.clone(),
+                stream_bps: r.stream_bps,
+                upload_bps: r.upload_bps,
                 score,
             }
         })
@@ -0,0 +1,377 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use ipnetwork::IpNetwork;
+use reqwest::{Client, StatusCode, header};
+use serde_json::Value;
+use tokio::io::AsyncReadExt;
+
+use crate::cache::{self, CachedBody};
+use crate::config::Config;
+use crate::ip;
+
+const CF_IPV4_URL: &str = "https://www.cloudflare.com/ips-v4/";
+const CF_IPV6_URL: &str = "https://www.cloudflare.com/ips-v6/";
+const FASTLY_URL: &str = "https://api.fastly.com/public-ip-list";
+const AWS_URL: &str = "https://ip-ranges.amazonaws.com/ip-ranges.json";
+
+/// 编译时内置的 Cloudflare IP 段快照, 网络和缓存均不可用时使用
+const EMBEDDED_IPV4: &str = include_str!("ranges/ips-v4.txt");
+const EMBEDDED_IPV6: &str = include_str!("ranges/ips-v6.txt");
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// IP 段的获取方式
+///
+/// - `cloudflare`: Cloudflare 官网的纯文本列表 (默认)
+/// - `fastly`: Fastly 公布的 JSON 列表
+/// - `cloudfront`: AWS `ip-ranges.json` 中 `service=CLOUDFRONT` 的前缀
+/// - `text:<URL>`: 每行一个 CIDR 的纯文本列表
+/// - `json:<URL>`: 任意列出前缀的 JSON 文档, 可配合 `--source-filter` 按字段筛选
+/// - `file:<PATH>`: 本地文件, 格式同 `--ip-file`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeSource {
+    Cloudflare,
+    Text {
+        url: String,
+    },
+    Json {
+        url: String,
+        filters: Vec<(String, String)>,
+    },
+    File {
+        path: String,
+    },
+}
+
+/// IP 段列表的来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeOrigin {
+    Live,
+    Cache,
+    Embedded,
+    File,
+}
+
+impl std::fmt::Display for RangeOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RangeOrigin::Live => write!(f, "在线 (live)"),
+            RangeOrigin::Cache => write!(f, "本地缓存 (cache)"),
+            RangeOrigin::Embedded => write!(f, "内置快照 (embedded)"),
+            RangeOrigin::File => write!(f, "本地文件 (file)"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RangeList {
+    pub name: String,
+    pub location: String,
+    pub origin: RangeOrigin,
+    pub ranges: Vec<IpNetwork>,
+    /// 来自 IP 文件中 `a-b` 地址段的地址块, 见 [`ip::IpList::spans`]
+    pub spans: Vec<IpNetwork>,
+}
+
+impl RangeSource {
+    /// 解析 `--source` 参数, `filters` 作用于 JSON 来源
+    pub fn parse(spec: &str, filters: &[(String, String)]) -> Result<Self> {
+        let source = match spec.split_once(':') {
+            _ if spec.eq_ignore_ascii_case("cloudflare") => RangeSource::Cloudflare,
+            _ if spec.eq_ignore_ascii_case("fastly") => RangeSource::Json {
+                url: FASTLY_URL.to_string(),
+                filters: filters.to_vec(),
+            },
+            _ if spec.eq_ignore_ascii_case("cloudfront") => {
+                let mut filters = filters.to_vec();
+                filters.push(("service".to_string(), "CLOUDFRONT".to_string()));
+                RangeSource::Json {
+                    url: AWS_URL.to_string(),
+                    filters,
+                }
+            }
+            Some(("text", url)) => RangeSource::Text {
+                url: url.to_string(),
+            },
+            Some(("json", url)) => RangeSource::Json {
+                url: url.to_string(),
+                filters: filters.to_vec(),
+            },
+            Some(("file", path)) => RangeSource::File {
+                path: path.to_string(),
+            },
+            _ => anyhow::bail!(
+                "无法识别的 IP 段来源 '{}' (可选: cloudflare, fastly, cloudfront, text:<URL>, json:<URL>, file:<PATH>)",
+                spec
+            ),
+        };
+        Ok(source)
+    }
+
+    pub async fn fetch(&self, client: &Client, include_ipv6: bool) -> Result<Vec<RangeList>> {
+        match self {
+            RangeSource::Cloudflare => Ok(fetch_cloudflare(client, include_ipv6).await),
+            RangeSource::Text { url } => {
+                let list = fetch_url(client, "文本列表", url, parse_cidr_lines).await?;
+                Ok(vec![retain_family(list, include_ipv6)])
+            }
+            RangeSource::Json { url, filters } => {
+                let parse = |body: &str| parse_json_prefixes(body, filters);
+                let list = fetch_url(client, "JSON 列表", url, parse).await?;
+                Ok(vec![retain_family(list, include_ipv6)])
+            }
+            RangeSource::File { path } => Ok(vec![load_file(path).await?]),
+        }
+    }
+}
+
+/// 根据命令行 (或 `.env` 中的 `CFIP_SOURCE`) 确定 IP 段来源, `--ip-file` 视为 `file:` 来源
+pub fn from_config(config: &Config) -> Result<Vec<RangeSource>> {
+    let filters = config
+        .source_filters
+        .iter()
+        .map(|f| {
+            f.split_once('=')
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+                .with_context(|| format!("--source-filter '{}' 格式应为 key=value", f))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut sources = config
+        .sources
+        .iter()
+        .map(|spec| RangeSource::parse(spec, &filters))
+        .collect::<Result<Vec<_>>>()?;
+    sources.extend(
+        config
+            .ip_files
+            .iter()
+            .map(|path| RangeSource::File { path: path.clone() }),
+    );
+
+    if sources.is_empty() {
+        sources.push(RangeSource::Cloudflare);
+    }
+    Ok(sources)
+}
+
+/// 依次获取所有来源的 IP 段
+pub async fn fetch_all(sources: &[RangeSource], include_ipv6: bool) -> Result<Vec<RangeList>> {
+    let client = Client::builder().timeout(FETCH_TIMEOUT).build()?;
+
+    let mut lists = Vec::new();
+    for source in sources {
+        lists.extend(source.fetch(&client, include_ipv6).await?);
+    }
+
+    if lists.iter().all(|list| list.ranges.is_empty()) {
+        anyhow::bail!("未获取到任何 IP 段");
+    }
+    Ok(lists)
+}
+
+/// 获取 Cloudflare IP 段, 依次尝试在线获取 (带缓存校验)、本地缓存和内置快照
+async fn fetch_cloudflare(client: &Client, include_ipv6: bool) -> Vec<RangeList> {
+    let mut targets = vec![("Cloudflare IPv4", CF_IPV4_URL, EMBEDDED_IPV4)];
+    if include_ipv6 {
+        targets.push(("Cloudflare IPv6", CF_IPV6_URL, EMBEDDED_IPV6));
+    }
+
+    let mut lists = Vec::new();
+    for (name, url, embedded) in targets {
+        let list = match fetch_url(client, name, url, parse_cidr_lines).await {
+            Ok(list) => list,
+            Err(e) => {
+                eprintln!("{}", format!("警告: {:#}，使用内置快照", e).yellow());
+                RangeList {
+                    name: name.to_string(),
+                    location: url.to_string(),
+                    origin: RangeOrigin::Embedded,
+                    ranges: parse_cidr_lines(embedded),
+                    spans: Vec::new(),
+                }
+            }
+        };
+        lists.push(list);
+    }
+    lists
+}
+
+/// 在线获取 IP 段, 失败时回退到本地缓存; 两者都不可用时返回错误
+async fn fetch_url<F>(client: &Client, name: &str, url: &str, parse: F) -> Result<RangeList>
+where
+    F: Fn(&str) -> Vec<IpNetwork>,
+{
+    let cache_name = cache_name_for(url);
+    let cached = cache::load(&cache_name).filter(|c| !parse(&c.body).is_empty());
+
+    let (origin, body) = match fetch_revalidated(client, url, cached.as_ref(), &parse).await {
+        Ok(Some(fresh)) => {
+            if let Err(e) = cache::save(&cache_name, &fresh) {
+                eprintln!("{} {:#}", "警告: 写入 IP 段缓存失败:".yellow(), e);
+            }
+            (RangeOrigin::Live, fresh.body)
+        }
+        // 304 Not Modified, 缓存仍然有效
+        Ok(None) => (RangeOrigin::Cache, cached.unwrap_or_default().body),
+        Err(e) => match cached {
+            Some(c) => {
+                eprintln!(
+                    "{}",
+                    format!("警告: 获取 {} 失败: {:#}，使用本地缓存", name, e).yellow()
+                );
+                (RangeOrigin::Cache, c.body)
+            }
+            None => return Err(e.context(format!("获取 {} 失败", name))),
+        },
+    };
+
+    Ok(RangeList {
+        name: name.to_string(),
+        location: url.to_string(),
+        origin,
+        ranges: parse(&body),
+        spans: Vec::new(),
+    })
+}
+
+/// 带 `If-None-Match`/`If-Modified-Since` 的请求, 返回 `None` 表示缓存未过期
+async fn fetch_revalidated<F>(
+    client: &Client,
+    url: &str,
+    cached: Option<&CachedBody>,
+    parse: &F,
+) -> Result<Option<CachedBody>>
+where
+    F: Fn(&str) -> Vec<IpNetwork>,
+{
+    let mut request = client.get(url);
+    if let Some(c) = cached {
+        if let Some(etag) = &c.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(modified) = &c.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, modified);
+        }
+    }
+
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+        return Ok(None);
+    }
+
+    let response = response.error_for_status()?;
+    let header_str = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let etag = header_str(header::ETAG);
+    let last_modified = header_str(header::LAST_MODIFIED);
+    let body = response.text().await?;
+
+    if parse(&body).is_empty() {
+        anyhow::bail!("响应中没有可用的 IP 段");
+    }
+
+    Ok(Some(CachedBody {
+        etag,
+        last_modified,
+        body,
+    }))
+}
+
+/// 读取本地 IP 文件, `-` 表示标准输入
+async fn load_file(path: &str) -> Result<RangeList> {
+    let body = if path == "-" {
+        let mut buf = String::new();
+        tokio::io::stdin()
+            .read_to_string(&mut buf)
+            .await
+            .context("读取标准输入失败")?;
+        buf
+    } else {
+        tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("读取 IP 文件 '{}' 失败", path))?
+    };
+
+    let list = ip::parse_ip_list(&body).with_context(|| format!("解析 IP 文件 '{}' 失败", path))?;
+
+    Ok(RangeList {
+        name: "IP 文件".to_string(),
+        location: path.to_string(),
+        origin: RangeOrigin::File,
+        ranges: list.ranges,
+        spans: list.spans,
+    })
+}
+
+fn retain_family(mut list: RangeList, include_ipv6: bool) -> RangeList {
+    if !include_ipv6 {
+        list.ranges.retain(|net| net.is_ipv4());
+    }
+    list
+}
+
+/// 缓存文件名, Cloudflare 列表沿用 `ips-v4`/`ips-v6`
+fn cache_name_for(url: &str) -> String {
+    match url {
+        CF_IPV4_URL => "ips-v4".to_string(),
+        CF_IPV6_URL => "ips-v6".to_string(),
+        _ => {
+            let sanitized: String = url
+                .trim_start_matches("https://")
+                .trim_start_matches("http://")
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            format!("source-{}", sanitized)
+        }
+    }
+}
+
+fn parse_cidr_lines(body: &str) -> Vec<IpNetwork> {
+    body.lines()
+        .filter_map(|line| line.trim().parse::<IpNetwork>().ok())
+        .collect()
+}
+
+/// 从 JSON 文档中收集所有可解析为 CIDR 的字符串
+///
+/// 对象中出现 `filters` 里的字段且取值不符 (忽略大小写) 时, 跳过该对象。
+fn parse_json_prefixes(body: &str, filters: &[(String, String)]) -> Vec<IpNetwork> {
+    fn collect(value: &Value, filters: &[(String, String)], out: &mut Vec<IpNetwork>) {
+        match value {
+            Value::Object(map) => {
+                let rejected = filters.iter().any(|(key, expected)| {
+                    map.get(key)
+                        .and_then(Value::as_str)
+                        .is_some_and(|actual| !actual.eq_ignore_ascii_case(expected))
+                });
+                if !rejected {
+                    map.values().for_each(|v| collect(v, filters, out));
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|v| collect(v, filters, out)),
+            Value::String(s) => {
+                if let Ok(net) = s.trim().parse::<IpNetwork>() {
+                    out.push(net);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut prefixes = Vec::new();
+    if let Ok(doc) = serde_json::from_str::<Value>(body) {
+        collect(&doc, filters, &mut prefixes);
+    }
+    prefixes.sort();
+    prefixes.dedup();
+    prefixes
+}
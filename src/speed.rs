@@ -1,18 +1,157 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
-use crate::config::Config;
+use crate::bind::Binding;
+use crate::colo;
+use crate::config::{Config, SpeedMetric, is_http_port};
 use crate::ping::PingResult;
+use crate::quic::QuicClient;
+use crate::rate::RateLimiter;
+use crate::status;
+use crate::throughput::{Sampler, Throughput};
+use crate::trace;
+
+/// 多连接合计速度与单连接速度之比达到该值时, 视为可能存在按连接限速
+const PER_FLOW_RATIO: f64 = 1.5;
 
 #[derive(Debug, Clone)]
 pub struct SpeedResult {
-    pub ip: IpAddr,
-    pub avg_latency: Duration,
-    pub loss_rate: f64,
+    pub ping: PingResult,
+    /// 按 `--speed-metric` 取的下载速度, 参与评分
     pub speed_bps: f64,
+    /// 下载的吞吐曲线和各项速度指标
+    pub throughput: Throughput,
+    /// `--connections` 大于 1 时的单连接速度, 此时 `speed_bps` 为多连接合计速度
+    pub stream_bps: Option<f64>,
+    /// 未启用上传测试或上传失败时为 `None`
+    pub upload_bps: Option<f64>,
+}
+
+/// 单次下载测试的结果
+pub struct Transfer {
+    pub throughput: Throughput,
+    /// `CF-RAY` 响应头中的节点代码
+    pub colo: Option<String>,
+}
+
+/// 在并发的测速任务间共享的参数
+struct SpeedTest {
+    url: reqwest::Url,
+    sni: String,
+    host: String,
+    binding: Binding,
+    quic: Option<QuicClient>,
+    upload: Option<Upload>,
+    connections: usize,
+    metric: SpeedMetric,
+    duration: Duration,
+}
+
+/// `--upload` 的参数, Host 和 SNI 默认取上传 URL 的域名
+struct Upload {
+    url: reqwest::Url,
+    sni: String,
+    host: String,
+    size: u64,
+}
+
+impl SpeedTest {
+    async fn download(&self, ip: IpAddr, port: u16) -> Result<Transfer> {
+        match &self.quic {
+            Some(quic) => {
+                let (authority, path) = h3_target(&self.url, &self.host, port);
+                let (throughput, colo) = quic
+                    .download(
+                        SocketAddr::new(ip, port),
+                        &self.sni,
+                        &authority,
+                        &path,
+                        self.duration,
+                    )
+                    .await?;
+                Ok(Transfer { throughput, colo })
+            }
+            None => {
+                test_download(
+                    &self.url,
+                    &self.binding,
+                    &self.sni,
+                    &self.host,
+                    ip,
+                    port,
+                    self.duration,
+                )
+                .await
+            }
+        }
+    }
+
+    /// 同时打开 `connections` 条连接下载, 合计各连接的数据量和吞吐曲线, 耗时取最慢的一条
+    async fn download_parallel(self: &Arc<Self>, ip: IpAddr, port: u16) -> Result<Transfer> {
+        let mut tasks = JoinSet::new();
+        for _ in 0..self.connections {
+            let test = self.clone();
+            tasks.spawn(async move { test.download(ip, port).await });
+        }
+
+        let mut parts = Vec::with_capacity(self.connections);
+        let mut colo = None;
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok(Ok(transfer)) = joined {
+                parts.push(transfer.throughput);
+                colo = colo.or(transfer.colo);
+            }
+        }
+        let throughput = Throughput::combine(&parts).context("所有连接均下载失败")?;
+        Ok(Transfer { throughput, colo })
+    }
+
+    /// 未启用 `--upload` 或上传失败时返回 `None`, 不影响下载结果
+    async fn upload(&self, ip: IpAddr, port: u16) -> Option<f64> {
+        let upload = self.upload.as_ref()?;
+        let throughput = match &self.quic {
+            Some(quic) => {
+                let (authority, path) = h3_target(&upload.url, &upload.host, port);
+                quic.upload(
+                    SocketAddr::new(ip, port),
+                    &upload.sni,
+                    &authority,
+                    &path,
+                    upload.size,
+                    self.duration,
+                )
+                .await
+            }
+            None => test_upload(upload, &self.binding, ip, port, self.duration).await,
+        };
+        throughput
+            .ok()
+            .map(|throughput| throughput.bps(self.metric))
+    }
+}
+
+/// HTTP/3 请求的 `:authority` 和 `:path`
+fn h3_target(url: &reqwest::Url, host: &str, port: u16) -> (String, String) {
+    let authority = match port {
+        443 => host.to_string(),
+        port => format!("{}:{}", host, port),
+    };
+    (authority, path_and_query(url))
+}
+
+fn path_and_query(url: &reqwest::Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
 }
 
 pub async fn test_speed(ping_results: &[PingResult], config: &Config) -> Result<Vec<SpeedResult>> {
@@ -22,64 +161,469 @@ pub async fn test_speed(ping_results: &[PingResult], config: &Config) -> Result<
     let pb = ProgressBar::new(count as u64);
     pb.set_style(
         ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.green/white} {pos}/{len} 速度测试中...")
+            .template("[{elapsed_precise}] {bar:40.green/white} {pos}/{len} 速度测试中... {msg}")
             .unwrap()
             .progress_chars("=> "),
     );
 
-    let mut results = Vec::with_capacity(count);
     let url = format!("{}?bytes={}", config.test_url, config.download_size);
-    let test_duration = Duration::from_secs(10);
+    let binding = Binding::from_config(config);
+    let quic = match config.speed_h3 {
+        true => Some(QuicClient::new(&binding)?),
+        false => None,
+    };
+    let upload = match config.upload {
+        true => {
+            let url = reqwest::Url::parse(&config.upload_url).context("无效的上传测试 URL")?;
+            let host = config
+                .host
+                .clone()
+                .unwrap_or_else(|| url.host_str().unwrap_or("speed.cloudflare.com").to_string());
+            Some(Upload {
+                sni: config.sni.clone().unwrap_or_else(|| host.clone()),
+                host,
+                url,
+                size: config.upload_size,
+            })
+        }
+        false => None,
+    };
+    let test = Arc::new(SpeedTest {
+        url: reqwest::Url::parse(&url).context("无效的速度测试 URL")?,
+        sni: config.tls_server_name(),
+        host: config.request_host(),
+        binding,
+        quic,
+        upload,
+        connections: config.connections.max(1),
+        metric: config.speed_metric,
+        duration: Duration::from_secs(10),
+    });
 
-    for candidate in candidates {
-        let ip = candidate.ip;
-        pb.set_message(format!("{}", ip));
+    let threads = config.speed_threads.max(1);
+    let semaphore = Arc::new(Semaphore::new(threads));
+    // 相邻两个测试的开始时间至少间隔 --speed-stagger
+    let stagger = Arc::new(RateLimiter::with_interval(Duration::from_millis(
+        config.speed_stagger_ms,
+    )));
+    let pb = Arc::new(pb);
 
-        match test_download(&url, ip, config.port, test_duration).await {
-            Ok(speed_bps) => {
-                results.push(SpeedResult {
-                    ip,
-                    avg_latency: candidate.avg_latency,
-                    loss_rate: candidate.loss_rate,
-                    speed_bps,
-                });
+    let mut tasks = JoinSet::new();
+    for (index, candidate) in candidates.iter().cloned().enumerate() {
+        let test = test.clone();
+        let semaphore = semaphore.clone();
+        let stagger = stagger.clone();
+        let pb = pb.clone();
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            stagger.acquire().await;
+            pb.set_message(candidate.ip.to_string());
+
+            // IP 速度测试失败则跳过
+            let begun = Instant::now();
+            let single = test.download(candidate.ip, candidate.port).await;
+            let parallel = match (&single, test.connections) {
+                (Ok(_), 2..) => test
+                    .download_parallel(candidate.ip, candidate.port)
+                    .await
+                    .ok(),
+                _ => None,
+            };
+            let ended = Instant::now();
+            let upload_bps = match single {
+                Ok(_) => test.upload(candidate.ip, candidate.port).await,
+                Err(_) => None,
+            };
+            pb.inc(1);
+            single.ok().map(|single| Finished {
+                index,
+                candidate,
+                window: (begun, ended),
+                single,
+                parallel,
+                upload_bps,
+            })
+        });
+    }
+
+    let mut finished = Vec::with_capacity(count);
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok(Some(done)) = joined {
+            finished.push(done);
+        }
+    }
+    pb.finish_and_clear();
+
+    // 按候选顺序输出, 与并发完成顺序无关
+    finished.sort_by_key(|done| done.index);
+
+    if threads > 1 && !finished.is_empty() {
+        let total_bytes: u64 = finished.iter().map(Finished::bytes).sum();
+        let best = finished
+            .iter()
+            .map(|done| done.download().throughput.avg_bps())
+            .fold(0.0, f64::max);
+        let active = active_time(finished.iter().map(|done| done.window));
+        println!(
+            "并发 {} 路测速: 总吞吐 {:.2} MB/s, 单 IP 最高 {:.2} MB/s",
+            threads,
+            total_bytes as f64 / active.as_secs_f64() / 1_048_576.0,
+            best / 1_048_576.0
+        );
+    }
+
+    let compared: Vec<_> = finished
+        .iter()
+        .filter_map(|done| {
+            Some((
+                done.single.throughput.bps(test.metric),
+                done.parallel.as_ref()?.throughput.bps(test.metric),
+            ))
+        })
+        .collect();
+    if !compared.is_empty() {
+        let limited = compared
+            .iter()
+            .filter(|(single, total)| *total >= single * PER_FLOW_RATIO)
+            .count();
+        println!(
+            "{} 连接合计速度达到单连接 {} 倍以上的 IP: {}/{}{}",
+            test.connections,
+            PER_FLOW_RATIO,
+            limited,
+            compared.len(),
+            if limited > 0 {
+                ", 可能存在按连接限速"
+            } else {
+                ""
             }
-            Err(_) => {
-                // IP 速度测试失败，跳过
+        );
+    }
+
+    Ok(finished
+        .into_iter()
+        .map(|done| {
+            let throughput = done.download().throughput.clone();
+            let stream_bps = done
+                .parallel
+                .as_ref()
+                .map(|_| done.single.throughput.bps(test.metric));
+            let mut ping = done.candidate;
+            ping.colo = ping.colo.or(done.single.colo);
+            SpeedResult {
+                ping,
+                speed_bps: throughput.bps(test.metric),
+                throughput,
+                stream_bps,
+                upload_bps: done.upload_bps,
             }
-        }
+        })
+        .collect())
+}
 
-        pb.inc(1);
+/// 单个 IP 的测速结果
+struct Finished {
+    index: usize,
+    candidate: PingResult,
+    /// 下载阶段的起止时刻, 不含上传测试
+    window: (Instant, Instant),
+    single: Transfer,
+    /// `--connections` 大于 1 时多连接同时下载的合计结果
+    parallel: Option<Transfer>,
+    upload_bps: Option<f64>,
+}
+
+impl Finished {
+    /// 参与评分的下载结果, 有多连接结果时取合计
+    fn download(&self) -> &Transfer {
+        self.parallel.as_ref().unwrap_or(&self.single)
     }
 
-    pb.finish_and_clear();
-    Ok(results)
+    fn bytes(&self) -> u64 {
+        self.single.throughput.bytes + self.parallel.as_ref().map_or(0, |t| t.throughput.bytes)
+    }
+}
+
+/// 至少有一个测试在进行的总时长, 错开启动留下的空档不计入总吞吐
+fn active_time(intervals: impl Iterator<Item = (Instant, Instant)>) -> Duration {
+    let mut intervals: Vec<_> = intervals.collect();
+    intervals.sort();
+
+    let mut total = Duration::ZERO;
+    let mut current: Option<(Instant, Instant)> = None;
+    for (begin, end) in intervals {
+        current = match current {
+            Some((b, e)) if begin <= e => Some((b, e.max(end))),
+            Some((b, e)) => {
+                total += e - b;
+                Some((begin, end))
+            }
+            None => Some((begin, end)),
+        };
+    }
+    if let Some((b, e)) = current {
+        total += e - b;
+    }
+    total
 }
 
-async fn test_download(url: &str, ip: IpAddr, port: u16, max_duration: Duration) -> Result<f64> {
-    let client = reqwest::Client::builder()
-        .resolve("speed.cloudflare.com", (ip, port).into())
-        .timeout(max_duration)
-        .danger_accept_invalid_certs(true)
+/// 通过 reqwest 下载并对收到的数据采样, 记录 `CF-RAY` 响应头中的节点代码
+///
+/// reqwest 会忽略 `resolve` 地址中的端口, 因此端口写入 URL; HTTP 端口上改用明文请求。
+/// reqwest 从 URL 取 SNI, 因此 URL 的域名替换为 `sni`, 再单独设置 `Host` 头, 并限定 HTTP/1.1。
+/// 证书按 `sni` 校验, 与上传、`--via` 下载和 HTTP 探测一致。
+async fn test_download(
+    url: &reqwest::Url,
+    binding: &Binding,
+    sni: &str,
+    host: &str,
+    ip: IpAddr,
+    port: u16,
+    max_duration: Duration,
+) -> Result<Transfer> {
+    let (url, host_header) = request_target(url, sni, host, port)?;
+
+    if binding.via.is_some() {
+        return download_via_tunnel(&url, &host_header, binding, ip, port, max_duration).await;
+    }
+
+    let client = binding
+        .apply_client(reqwest::Client::builder())
+        .resolve(sni, (ip, port).into())
+        // HTTP/2 的 :authority 取自 URL (即 SNI), 与 Host 头不一致时服务器可能视为畸形请求
+        .http1_only()
         .build()?;
 
-    let start = Instant::now();
-    let response = client.get(url).send().await?;
+    // 不使用 reqwest 的总超时: 它在读取响应体时到期会丢弃已收到的数据
+    let mut sampler = Sampler::start();
+    let deadline = tokio::time::Instant::now() + max_duration;
+    let request = client
+        .get(url)
+        .header(reqwest::header::HOST, host_header)
+        .send();
+    let response = tokio::time::timeout_at(deadline, request)
+        .await
+        .context("速度测试请求超时")??
+        .error_for_status()?;
+    let colo = response
+        .headers()
+        .get("cf-ray")
+        .and_then(|v| v.to_str().ok())
+        .and_then(colo::from_cf_ray);
 
-    let mut total_bytes: u64 = 0;
     let mut stream = response;
 
-    while let Some(chunk) = stream.chunk().await? {
-        total_bytes += chunk.len() as u64;
-        if start.elapsed() >= max_duration {
+    // 到达测试时长时停止, 已收到的数据照常计算
+    while let Ok(chunk) = tokio::time::timeout_at(deadline, stream.chunk()).await {
+        let Some(chunk) = chunk? else {
             break;
+        };
+        sampler.record(chunk.len() as u64);
+    }
+
+    Ok(Transfer {
+        throughput: sampler.finish()?,
+        colo,
+    })
+}
+
+/// 按候选端口改写 URL: HTTP 端口用明文, 域名替换为 `sni`, 返回 URL 和 `Host` 头
+fn request_target(
+    url: &reqwest::Url,
+    sni: &str,
+    host: &str,
+    port: u16,
+) -> Result<(reqwest::Url, String)> {
+    let mut url = url.clone();
+    if is_http_port(port) {
+        let _ = url.set_scheme("http");
+    }
+    let _ = url.set_port(Some(port));
+    url.set_host(Some(sni)).context("无效的 SNI")?;
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    Ok((url, host_header))
+}
+
+/// 明文或 TLS 连接, 统一按同一种流读写
+trait HttpStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> HttpStream for S {}
+
+/// 连接候选 IP (经 `--via` 时走隧道), https 时以 URL 的域名作为 SNI 完成 TLS 握手
+async fn open_stream(
+    url: &reqwest::Url,
+    binding: &Binding,
+    ip: IpAddr,
+    port: u16,
+    deadline: tokio::time::Instant,
+) -> Result<Box<dyn HttpStream>> {
+    let stream = tokio::time::timeout_at(deadline, binding.connect(SocketAddr::new(ip, port)))
+        .await??
+        .stream;
+    if url.scheme() != "https" {
+        return Ok(Box::new(stream));
+    }
+
+    let sni = url.host_str().unwrap_or_default().to_string();
+    let server_name = ServerName::try_from(sni).context("无效的 SNI")?;
+    let tls = tokio::time::timeout_at(
+        deadline,
+        trace::tls_connector()?.connect(server_name, stream),
+    )
+    .await??;
+    Ok(Box::new(tls))
+}
+
+/// 经 `--via` 代理隧道下载
+///
+/// reqwest 的 HTTP 代理会把域名交给代理解析, 无法指定候选 IP,
+/// 因此直接在隧道上发送 HTTP/1.1 请求, 读到连接关闭或超时为止。
+async fn download_via_tunnel(
+    url: &reqwest::Url,
+    host_header: &str,
+    binding: &Binding,
+    ip: IpAddr,
+    port: u16,
+    max_duration: Duration,
+) -> Result<Transfer> {
+    let mut sampler = Sampler::start();
+    let deadline = tokio::time::Instant::now() + max_duration;
+    let stream = open_stream(url, binding, ip, port, deadline).await?;
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: cfip\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        path_and_query(url),
+        host_header
+    );
+    let colo = read_response(stream, &request, deadline, &mut sampler).await?;
+
+    Ok(Transfer {
+        throughput: sampler.finish()?,
+        colo,
+    })
+}
+
+/// 以 HTTP/1.1 POST 上传 `size` 字节的零并对已发送的数据采样, 到达测试时长时按已发送的数据量计算
+///
+/// 连接建立后才开始计时; 请求体发完后等待服务器响应, 确认数据已被接收。
+async fn test_upload(
+    upload: &Upload,
+    binding: &Binding,
+    ip: IpAddr,
+    port: u16,
+    max_duration: Duration,
+) -> Result<Throughput> {
+    let deadline = tokio::time::Instant::now() + max_duration;
+    let (url, host_header) = request_target(&upload.url, &upload.sni, &upload.host, port)?;
+    let mut stream = open_stream(&url, binding, ip, port, deadline).await?;
+
+    let mut sampler = Sampler::start();
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: cfip\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path_and_query(&url),
+        host_header,
+        upload.size
+    );
+    tokio::time::timeout_at(deadline, stream.write_all(head.as_bytes())).await??;
+
+    let chunk = vec![0u8; 64 * 1024];
+    let mut sent_bytes: u64 = 0;
+    while sent_bytes < upload.size {
+        let n = (upload.size - sent_bytes).min(chunk.len() as u64) as usize;
+        match tokio::time::timeout_at(deadline, stream.write_all(&chunk[..n])).await {
+            Ok(result) => result?,
+            Err(_) => break,
         }
+        sampler.record(n as u64);
+        sent_bytes += n as u64;
+    }
+
+    if sent_bytes == upload.size
+        && let Ok(result) = tokio::time::timeout_at(deadline, read_status(&mut stream)).await
+    {
+        result?;
     }
 
-    let elapsed = start.elapsed().as_secs_f64();
-    if elapsed < 0.001 || total_bytes == 0 {
-        anyhow::bail!("下载数据不足");
+    sampler.finish()
+}
+
+/// 读取响应头并检查状态码
+async fn read_status<S>(stream: &mut S) -> Result<()>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("上传测试未收到响应");
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    if !status::is_success(&head) {
+        let text = String::from_utf8_lossy(&head);
+        anyhow::bail!(
+            "上传测试请求失败: {}",
+            text.lines().next().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// 发送请求并对响应体采样, 同时解析状态码和 `CF-RAY`
+async fn read_response<S>(
+    mut stream: S,
+    request: &str,
+    deadline: tokio::time::Instant,
+    sampler: &mut Sampler,
+) -> Result<Option<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut head = Vec::new();
+    let mut in_body = false;
+    let mut colo = None;
+    let mut buf = vec![0u8; 64 * 1024];
+
+    loop {
+        let n = match tokio::time::timeout_at(deadline, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => n,
+            // 部分服务器关闭连接时不发送 close_notify
+            Ok(Err(_)) if in_body => break,
+            Ok(Err(e)) => return Err(e.into()),
+        };
+
+        if in_body {
+            sampler.record(n as u64);
+            continue;
+        }
+
+        head.extend_from_slice(&buf[..n]);
+        let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") else {
+            continue;
+        };
+        let text = String::from_utf8_lossy(&head[..end]).to_string();
+        let mut lines = text.lines();
+        let status_line = lines.next().unwrap_or_default();
+        if !status::is_success(status_line.as_bytes()) {
+            anyhow::bail!("速度测试请求失败: {}", status_line);
+        }
+        colo = lines
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("cf-ray"))
+            .and_then(|(_, value)| colo::from_cf_ray(value.trim()));
+        sampler.record((head.len() - end - 4) as u64);
+        in_body = true;
     }
 
-    Ok(total_bytes as f64 / elapsed)
+    Ok(colo)
 }
@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use crate::config::LatencyMetric;
+
+/// 一组延迟样本的分布统计
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub max: Duration,
+    pub avg: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub stddev: Duration,
+    /// 相邻两次样本差值绝对值的平均
+    pub jitter: Duration,
+}
+
+impl LatencyStats {
+    /// 按测试顺序的样本计算统计, `trim` 时先按 Tukey 规则 (Q3 + 1.5 IQR) 剔除偏高的离群值
+    pub fn from_samples(samples: &[Duration], trim: bool) -> Option<Self> {
+        let samples = if trim {
+            trim_outliers(samples)
+        } else {
+            samples.to_vec()
+        };
+        if samples.is_empty() {
+            return None;
+        }
+
+        let secs: Vec<f64> = samples.iter().map(Duration::as_secs_f64).collect();
+        let mut sorted = secs.clone();
+        sorted.sort_by(f64::total_cmp);
+
+        let n = secs.len() as f64;
+        let avg = secs.iter().sum::<f64>() / n;
+        let variance = secs.iter().map(|s| (s - avg).powi(2)).sum::<f64>() / n;
+        let jitter = if secs.len() > 1 {
+            secs.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / (n - 1.0)
+        } else {
+            0.0
+        };
+
+        Some(Self {
+            min: Duration::from_secs_f64(sorted[0]),
+            max: Duration::from_secs_f64(sorted[sorted.len() - 1]),
+            avg: Duration::from_secs_f64(avg),
+            median: Duration::from_secs_f64(percentile(&sorted, 0.5)),
+            p95: Duration::from_secs_f64(percentile(&sorted, 0.95)),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+            jitter: Duration::from_secs_f64(jitter),
+        })
+    }
+
+    pub fn metric(&self, metric: LatencyMetric) -> Duration {
+        match metric {
+            LatencyMetric::Avg => self.avg,
+            LatencyMetric::Median => self.median,
+            LatencyMetric::P95 => self.p95,
+        }
+    }
+}
+
+/// 中位数取中间两值的平均, 其余分位数采用最近秩法
+pub fn percentile(sorted: &[f64], q: f64) -> f64 {
+    let n = sorted.len();
+    if q == 0.5 && n.is_multiple_of(2) {
+        return (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0;
+    }
+    let rank = (q * n as f64).ceil() as usize;
+    sorted[rank.clamp(1, n) - 1]
+}
+
+/// 样本少于 4 个时不做剔除, 保持原有顺序
+fn trim_outliers(samples: &[Duration]) -> Vec<Duration> {
+    if samples.len() < 4 {
+        return samples.to_vec();
+    }
+
+    let mut sorted: Vec<f64> = samples.iter().map(Duration::as_secs_f64).collect();
+    sorted.sort_by(f64::total_cmp);
+    let q1 = percentile(&sorted, 0.25);
+    let q3 = percentile(&sorted, 0.75);
+    let upper = q3 + 1.5 * (q3 - q1);
+
+    samples
+        .iter()
+        .copied()
+        .filter(|s| s.as_secs_f64() <= upper)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(values: &[u64]) -> Vec<Duration> {
+        values.iter().map(|&v| Duration::from_millis(v)).collect()
+    }
+
+    #[test]
+    fn percentile_median_even_and_odd() {
+        assert_eq!(percentile(&[1.0, 2.0, 3.0, 4.0], 0.5), 2.5);
+        assert_eq!(percentile(&[1.0, 2.0, 3.0, 4.0, 5.0], 0.5), 3.0);
+        assert_eq!(percentile(&[7.0], 0.5), 7.0);
+    }
+
+    #[test]
+    fn percentile_nearest_rank() {
+        let sorted = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(percentile(&sorted, 0.25), 1.0);
+        assert_eq!(percentile(&sorted, 0.75), 3.0);
+        assert_eq!(percentile(&sorted, 0.95), 4.0);
+
+        let sorted: Vec<f64> = (1..=20).map(f64::from).collect();
+        assert_eq!(percentile(&sorted, 0.95), 19.0);
+    }
+
+    #[test]
+    fn percentile_bounds() {
+        let sorted = [1.0, 2.0, 3.0];
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(percentile(&sorted, 1.0), 3.0);
+    }
+
+    #[test]
+    fn trim_outliers_drops_high_outlier_keeping_order() {
+        assert_eq!(
+            trim_outliers(&ms(&[12, 100, 10, 13, 11])),
+            ms(&[12, 10, 13, 11])
+        );
+    }
+
+    #[test]
+    fn trim_outliers_keeps_low_values() {
+        let samples = ms(&[1, 50, 51, 52, 53]);
+        assert_eq!(trim_outliers(&samples), samples);
+    }
+
+    #[test]
+    fn trim_outliers_needs_four_samples() {
+        let samples = ms(&[10, 1000, 10]);
+        assert_eq!(trim_outliers(&samples), samples);
+    }
+}
@@ -0,0 +1,48 @@
+/// 从 HTTP/1.x 响应头解析状态行中的状态码
+///
+/// `head` 可以是完整响应或只包含响应头, 只读取第一行。
+pub fn status_code(head: &[u8]) -> Option<u16> {
+    let end = head.iter().position(|&b| b == b'\n').unwrap_or(head.len());
+    let line = std::str::from_utf8(&head[..end]).ok()?;
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// 状态码是否为 2xx
+pub fn is_success(head: &[u8]) -> bool {
+    status_code(head).is_some_and(|code| (200..300).contains(&code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status_line() {
+        assert_eq!(status_code(b"HTTP/1.1 200 OK\r\n\r\n"), Some(200));
+        assert_eq!(
+            status_code(b"HTTP/1.0 407 Proxy Authentication Required\r\n"),
+            Some(407)
+        );
+        assert_eq!(status_code(b"HTTP/1.1 204"), Some(204));
+    }
+
+    #[test]
+    fn rejects_malformed_status_line() {
+        assert_eq!(status_code(b""), None);
+        assert_eq!(status_code(b"HTTP/1.1\r\n"), None);
+        assert_eq!(status_code(b"HTTP/1.1 OK\r\n"), None);
+        assert_eq!(status_code(b"SSH-2.0-OpenSSH\r\n"), None);
+    }
+
+    #[test]
+    fn success_is_2xx_only() {
+        assert!(is_success(b"HTTP/1.1 200 Connection established\r\n\r\n"));
+        assert!(is_success(b"HTTP/1.1 206 Partial Content\r\n"));
+        assert!(!is_success(b"HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(!is_success(b"HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+}
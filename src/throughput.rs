@@ -0,0 +1,293 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::config::SpeedMetric;
+use crate::stats::percentile;
+
+/// 吞吐曲线的采样间隔
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// 首字节之后的这段时间视为 TCP 慢启动, 不计入稳定速度
+const WARMUP: Duration = Duration::from_secs(1);
+
+/// 按固定间隔统计传输的字节数
+pub struct Sampler {
+    start: Instant,
+    first_byte: Option<Duration>,
+    bytes: u64,
+    buckets: Vec<u64>,
+}
+
+impl Sampler {
+    /// 从现在开始计时
+    pub fn start() -> Self {
+        Self {
+            start: Instant::now(),
+            first_byte: None,
+            bytes: 0,
+            buckets: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, n: u64) {
+        if n == 0 {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        self.first_byte.get_or_insert(elapsed);
+        let index = bucket(elapsed);
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += n;
+        self.bytes += n;
+    }
+
+    pub fn finish(self) -> Result<Throughput> {
+        let elapsed = self.start.elapsed();
+        let Some(ttfb) = self.first_byte else {
+            anyhow::bail!("传输数据不足");
+        };
+        if elapsed.as_secs_f64() < 0.001 {
+            anyhow::bail!("传输数据不足");
+        }
+
+        // 首字节和结束时所在的区间不完整, 曲线只保留两者之间的完整区间
+        let first = bucket(ttfb) + 1;
+        let last = bucket(elapsed);
+        let curve = (first..last)
+            .map(|i| {
+                self.buckets.get(i).copied().unwrap_or(0) as f64 / SAMPLE_INTERVAL.as_secs_f64()
+            })
+            .collect();
+
+        Ok(Throughput {
+            bytes: self.bytes,
+            elapsed,
+            ttfb,
+            curve_start: SAMPLE_INTERVAL * first as u32,
+            curve,
+        })
+    }
+}
+
+fn bucket(elapsed: Duration) -> usize {
+    (elapsed.as_nanos() / SAMPLE_INTERVAL.as_nanos()) as usize
+}
+
+/// 一次传输的数据量、耗时和吞吐曲线
+#[derive(Debug, Clone)]
+pub struct Throughput {
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// 从开始计时到收到 (或发出) 第一个字节
+    pub ttfb: Duration,
+    /// `curve[0]` 对应区间相对开始计时的起点
+    pub curve_start: Duration,
+    /// 每个采样区间的速度 (字节/秒)
+    pub curve: Vec<f64>,
+}
+
+impl Throughput {
+    /// 同时进行的多条传输的合计, 曲线取各条曲线都覆盖的区间逐点相加
+    pub fn combine(parts: &[Throughput]) -> Option<Self> {
+        let bytes = parts.iter().map(|p| p.bytes).sum();
+        let elapsed = parts.iter().map(|p| p.elapsed).max()?;
+        let ttfb = parts.iter().map(|p| p.ttfb).min()?;
+
+        let first = parts.iter().map(|p| bucket(p.curve_start)).max()?;
+        let last = parts
+            .iter()
+            .map(|p| bucket(p.curve_start) + p.curve.len())
+            .min()?;
+        let curve = (first..last)
+            .map(|i| {
+                parts
+                    .iter()
+                    .map(|p| p.curve[i - bucket(p.curve_start)])
+                    .sum()
+            })
+            .collect();
+
+        Some(Self {
+            bytes,
+            elapsed,
+            ttfb,
+            curve_start: SAMPLE_INTERVAL * first as u32,
+            curve,
+        })
+    }
+
+    /// 总数据量除以总耗时, 包含建立连接、TLS 握手和慢启动
+    pub fn avg_bps(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// 首字节后预热期之外各区间速度的中位数; 传输过短时放宽到全部区间, 仍没有则取平均
+    pub fn steady_bps(&self) -> f64 {
+        let warm = self.ttfb + WARMUP;
+        let mut steady: Vec<f64> = self
+            .curve
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.curve_start + SAMPLE_INTERVAL * *i as u32 >= warm)
+            .map(|(_, &bps)| bps)
+            .collect();
+        if steady.is_empty() {
+            steady = self.curve.clone();
+        }
+        if steady.is_empty() {
+            return self.avg_bps();
+        }
+        steady.sort_by(f64::total_cmp);
+        percentile(&steady, 0.5)
+    }
+
+    pub fn peak_bps(&self) -> f64 {
+        self.curve
+            .iter()
+            .copied()
+            .reduce(f64::max)
+            .unwrap_or_else(|| self.avg_bps())
+    }
+
+    pub fn bps(&self, metric: SpeedMetric) -> f64 {
+        match metric {
+            SpeedMetric::Avg => self.avg_bps(),
+            SpeedMetric::Steady => self.steady_bps(),
+            SpeedMetric::Peak => self.peak_bps(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// 已运行 `elapsed`、在 `ttfb` 收到首字节的采样器
+    fn sampler(ttfb: Duration, elapsed: Duration, buckets: Vec<u64>) -> Sampler {
+        Sampler {
+            start: Instant::now() - elapsed,
+            first_byte: Some(ttfb),
+            bytes: buckets.iter().sum(),
+            buckets,
+        }
+    }
+
+    fn throughput(ttfb: Duration, curve_start: Duration, curve: Vec<f64>) -> Throughput {
+        Throughput {
+            bytes: 1000,
+            elapsed: ms(4000),
+            ttfb,
+            curve_start,
+            curve,
+        }
+    }
+
+    #[test]
+    fn finish_drops_partial_first_and_last_buckets() {
+        // 首字节在区间 1, 结束于区间 8: 只保留区间 2..8
+        let buckets = vec![0, 50, 100, 200, 300, 400, 500, 600, 70];
+        let result = sampler(ms(300), ms(2100), buckets).finish().unwrap();
+
+        assert_eq!(result.bytes, 2220);
+        assert_eq!(result.ttfb, ms(300));
+        assert_eq!(result.curve_start, ms(500));
+        assert_eq!(
+            result.curve,
+            vec![400.0, 800.0, 1200.0, 1600.0, 2000.0, 2400.0]
+        );
+    }
+
+    #[test]
+    fn finish_fills_silent_buckets_with_zero() {
+        let result = sampler(ms(100), ms(1100), vec![10, 20]).finish().unwrap();
+        assert_eq!(result.curve_start, ms(250));
+        assert_eq!(result.curve, vec![80.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn finish_without_data_fails() {
+        let mut empty = sampler(ms(0), ms(1000), Vec::new());
+        empty.first_byte = None;
+        assert!(empty.finish().is_err());
+    }
+
+    #[test]
+    fn short_transfer_falls_back_to_average() {
+        let result = sampler(ms(50), ms(200), vec![1000]).finish().unwrap();
+        assert!(result.curve.is_empty());
+        let avg = result.avg_bps();
+        assert_eq!(result.steady_bps(), avg);
+        assert_eq!(result.peak_bps(), avg);
+    }
+
+    #[test]
+    fn steady_skips_warmup() {
+        // 预热期截止于 1250ms, 曲线从 500ms 开始, 前三个区间属于慢启动
+        let t = throughput(ms(250), ms(500), vec![1.0, 2.0, 3.0, 30.0, 10.0, 20.0]);
+        assert_eq!(t.steady_bps(), 20.0);
+        assert_eq!(t.peak_bps(), 30.0);
+    }
+
+    #[test]
+    fn steady_uses_whole_curve_when_all_in_warmup() {
+        let t = throughput(ms(250), ms(500), vec![1.0, 4.0, 2.0]);
+        assert_eq!(t.steady_bps(), 2.0);
+        assert_eq!(t.peak_bps(), 4.0);
+    }
+
+    #[test]
+    fn bps_selects_metric() {
+        let t = throughput(
+            ms(0),
+            ms(250),
+            vec![100.0, 300.0, 200.0, 400.0, 500.0, 600.0],
+        );
+        assert_eq!(t.bps(SpeedMetric::Avg), 250.0);
+        assert_eq!(t.bps(SpeedMetric::Steady), 500.0);
+        assert_eq!(t.bps(SpeedMetric::Peak), 600.0);
+    }
+
+    #[test]
+    fn combine_sums_the_overlapping_buckets() {
+        // 两条曲线分别覆盖区间 2..6 和 3..8, 重叠部分为 3..6
+        let a = throughput(ms(300), ms(500), vec![1.0, 2.0, 3.0, 4.0]);
+        let b = Throughput {
+            bytes: 500,
+            elapsed: ms(5000),
+            ..throughput(ms(600), ms(750), vec![10.0, 20.0, 30.0, 40.0, 50.0])
+        };
+        let total = Throughput::combine(&[a, b]).unwrap();
+
+        assert_eq!(total.bytes, 1500);
+        assert_eq!(total.elapsed, ms(5000));
+        assert_eq!(total.ttfb, ms(300));
+        assert_eq!(total.curve_start, ms(750));
+        assert_eq!(total.curve, vec![12.0, 23.0, 34.0]);
+    }
+
+    #[test]
+    fn combine_with_an_empty_curve() {
+        let a = throughput(ms(300), ms(500), vec![1.0, 2.0, 3.0]);
+        let b = throughput(ms(100), ms(250), Vec::new());
+        let total = Throughput::combine(&[a, b]).unwrap();
+
+        assert!(total.curve.is_empty());
+        assert_eq!(total.ttfb, ms(100));
+        assert_eq!(total.peak_bps(), total.avg_bps());
+    }
+
+    #[test]
+    fn combine_without_overlap_or_parts() {
+        let a = throughput(ms(0), ms(250), vec![1.0]);
+        let b = throughput(ms(0), ms(1000), vec![2.0]);
+        assert!(Throughput::combine(&[a, b]).unwrap().curve.is_empty());
+        assert!(Throughput::combine(&[]).is_none());
+    }
+}
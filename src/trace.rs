@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::TlsConnector;
+
+use crate::bind::Binding;
+use crate::concurrency::is_local_error;
+use crate::config::is_http_port;
+use crate::status;
+
+const TRACE_PATH: &str = "/cdn-cgi/trace";
+
+/// 单次 HTTP 探测的各阶段耗时
+#[derive(Debug, Clone, Copy)]
+pub struct HttpTiming {
+    pub tcp_connect: Duration,
+    /// 明文 HTTP 端口上为 None
+    pub tls_handshake: Option<Duration>,
+    pub ttfb: Duration,
+}
+
+impl HttpTiming {
+    /// 完整请求耗时 (连接 + 握手 + 首字节), 作为 HTTP 探测的延迟样本
+    pub fn total(&self) -> Duration {
+        self.tcp_connect + self.tls_handshake.unwrap_or_default() + self.ttfb
+    }
+}
+
+/// 单次 HTTP 探测的结果
+#[derive(Debug)]
+pub enum HttpOutcome {
+    /// 附带 trace 中的 `colo=` 字段
+    Ok(HttpTiming, Option<String>),
+    /// 连接、握手或读取失败 (计入丢包)
+    Failed,
+    /// 本机资源不足, 未能发起连接 (不计入丢包)
+    Local,
+    /// 收到了响应, 但不是有效的 Cloudflare trace
+    Invalid,
+}
+
+/// 构建探测使用的 TLS 连接器, 使用内置的 webpki 根证书校验
+pub fn tls_connector() -> Result<TlsConnector> {
+    let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// 完成 TLS 握手并请求 `/cdn-cgi/trace`, 每个阶段分别受 `timeout` 限制
+///
+/// 握手使用 `sni` 作为服务器名称, 请求的 `Host` 头为 `host`。
+/// Cloudflare 的 HTTP 端口 (80、8080 等) 上跳过 TLS 握手, 直接发送明文请求。
+/// 经 `--via` 代理时各阶段耗时均扣除本机到代理的往返, 近似为代理到目标这一段。
+pub async fn http_ping(
+    connector: &TlsConnector,
+    binding: &Binding,
+    ip: IpAddr,
+    port: u16,
+    sni: &str,
+    host: &str,
+    timeout: Duration,
+) -> HttpOutcome {
+    let Ok(server_name) = ServerName::try_from(sni.to_string()) else {
+        return HttpOutcome::Failed;
+    };
+
+    let connection =
+        match tokio::time::timeout(timeout, binding.connect(SocketAddr::new(ip, port))).await {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(e)) if is_local_error(&e) => return HttpOutcome::Local,
+            _ => return HttpOutcome::Failed,
+        };
+    let stream = connection.stream;
+    // 握手 (TLS 1.3 为一个往返) 和首字节各扣除一次本机到代理的往返, 直连时为 0
+    let beyond_proxy = |elapsed: Duration| elapsed.saturating_sub(connection.proxy_rtt);
+
+    let (tls_handshake, exchange) = if is_http_port(port) {
+        (None, request_trace(stream, host, timeout).await)
+    } else {
+        let start = Instant::now();
+        let tls = match tokio::time::timeout(timeout, connector.connect(server_name, stream)).await
+        {
+            Ok(Ok(tls)) => tls,
+            _ => return HttpOutcome::Failed,
+        };
+        (
+            Some(beyond_proxy(start.elapsed())),
+            request_trace(tls, host, timeout).await,
+        )
+    };
+    let Some((ttfb, response)) = exchange else {
+        return HttpOutcome::Failed;
+    };
+    let ttfb = beyond_proxy(ttfb);
+
+    match parse_trace_response(&response) {
+        Some(fields) => HttpOutcome::Ok(
+            HttpTiming {
+                tcp_connect: connection.connect,
+                tls_handshake,
+                ttfb,
+            },
+            fields.get("colo").map(|c| c.to_ascii_uppercase()),
+        ),
+        None => HttpOutcome::Invalid,
+    }
+}
+
+/// 发送 trace 请求, 返回首字节耗时和完整响应
+async fn request_trace<S>(
+    mut stream: S,
+    host: &str,
+    timeout: Duration,
+) -> Option<(Duration, Vec<u8>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: cfip\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        TRACE_PATH, host
+    );
+
+    let start = Instant::now();
+    stream.write_all(request.as_bytes()).await.ok()?;
+
+    let mut first = [0u8; 1];
+    tokio::time::timeout(timeout, stream.read_exact(&mut first))
+        .await
+        .ok()?
+        .ok()?;
+    let ttfb = start.elapsed();
+
+    // 部分服务器关闭连接时不发送 close_notify, 读取出错时仍使用已收到的数据
+    let mut response = first.to_vec();
+    if tokio::time::timeout(timeout, stream.read_to_end(&mut response))
+        .await
+        .is_err()
+    {
+        return None;
+    }
+
+    Some((ttfb, response))
+}
+
+/// 解析 trace 响应, 状态码为 200 且包含 `ip=`、`colo=` 等字段才视为有效
+pub fn parse_trace_response(response: &[u8]) -> Option<HashMap<String, String>> {
+    let text = String::from_utf8_lossy(response);
+    let (head, body) = text.split_once("\r\n\r\n")?;
+
+    if status::status_code(head.as_bytes()) != Some(200) {
+        return None;
+    }
+
+    let fields: HashMap<String, String> = body
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+    ["fl", "ip", "colo"]
+        .iter()
+        .all(|key| fields.contains_key(*key))
+        .then_some(fields)
+}